@@ -0,0 +1,125 @@
+//////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+/// refs:
+/// - https://en.wikipedia.org/wiki/Pipeline_(software)
+/// - https://go.dev/blog/pipelines: highlights an essential challenge - stages not exiting when they should, resulting in resource leak.
+///
+/// A pipeline is a series of stages connected by channels
+/// In each stage:
+///     - receive values from upstream via inbound channels
+///     - perform some function on that data, usually producing new values
+///     - send values downstream via outbound channels
+///
+//////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+/// The use of channels for communication between stages means that stages can also be run in parallel.
+///
+/// This use case here is the following steps:
+///     - generate numbers
+///     - square them, using several workers
+///     - merge the results from the various workers
+///
+/// Run it with `cargo run --example pipeline`, followed by the level to log down to
+/// (error, warn, info, debug or trace) to see what the stages do [default: warn].
+use std::env;
+use std::error::Error;
+use std::io::{self, Stdout, Write};
+use std::time::Duration;
+
+use rconcurrency_stuff::event;
+use rconcurrency_stuff::numeric::{Overflow, OverflowError};
+use rconcurrency_stuff::sink::WriteTo;
+use rconcurrency_stuff::{
+    Batch, FanOut, Level, PanicPolicy, Pipeline, Source, StageConfig, StderrLogger, Watchdog,
+};
+
+const WORKERS: usize = 2;
+
+// Each boundary between two stages has its own type,
+// so wiring e.g. "merge" right after "generate" does not compile.
+#[derive(Debug)]
+struct Generated(u64);
+#[derive(Debug)]
+struct Squared(u64);
+#[derive(Debug)]
+struct Merged(u64);
+
+fn generate(start: u64, count: usize) -> Source<Generated> {
+    // The source stops after `count` numbers, dropping its sender.
+    Source::new((start..=u64::MAX).map(|num| {
+        event!(Level::Info, "generated {}", num);
+        Generated(num)
+    }))
+    .take(count)
+}
+
+// Each number of a batch is squared on its own: one too big to be squared
+// doesn't take the others down with it.
+fn square(batch: Vec<Generated>) -> Vec<Result<Squared, OverflowError<u64>>> {
+    batch
+        .into_iter()
+        .map(|Generated(num)| {
+            event!(Level::Info, "squaring {}", num);
+            Overflow::Checked.square(&num).map(Squared)
+        })
+        .collect()
+}
+
+fn merge(squared: Result<Squared, OverflowError<u64>>) -> Result<Merged, OverflowError<u64>> {
+    let Squared(squared) = squared?;
+    event!(Level::Info, "merge received {}", squared);
+    Ok(Merged(squared))
+}
+
+fn write(out: &mut Stdout, Merged(squared): Merged) -> io::Result<()> {
+    writeln!(out, "result {}", squared)
+}
+
+fn log_level(arg: Option<String>) -> Result<Level, String> {
+    match arg.as_deref() {
+        None | Some("warn") => Ok(Level::Warn),
+        Some("error") => Ok(Level::Error),
+        Some("info") => Ok(Level::Info),
+        Some("debug") => Ok(Level::Debug),
+        Some("trace") => Ok(Level::Trace),
+        Some(other) => Err(format!(
+            "unknown log level {:?}: expected error, warn, info, debug or trace",
+            other
+        )),
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let level = log_level(env::args().nth(1))?;
+    // generate -> batch -> round-robin -> square xN -> unbatch -> merge -> stdout
+    Pipeline::source("generate", generate(2, 10))
+        // Errors are logged as warnings as well.
+        .logger(StderrLogger::new(level))
+        // Should a stage fail, the others are cancelled: any of them still running a second
+        // later is logged along with what it is blocked on, instead of the demo hanging silently.
+        .watchdog(Watchdog::new(Duration::from_secs(1)))
+        // The workers square the numbers a batch at a time, rather than paying for the
+        // links between the stages for each of them.
+        .batch(
+            "batch",
+            Batch::new()
+                .max_items(4)
+                .max_latency(Duration::from_millis(10)),
+        )
+        // A panicking worker only loses the batch it was working on, and the results
+        // come out in the order the numbers were generated.
+        .fan_out(
+            StageConfig::new("square").on_panic(PanicPolicy::Restart),
+            FanOut::new().workers(WORKERS).ordered(2 * WORKERS),
+            square,
+        )
+        .unbatch("unbatch")
+        // Numbers too big to be squared are reported without stopping the pipeline.
+        .try_stage("merge", merge)
+        // Once "generate" stops, "batch" sends out what is left and stops as well, then the
+        // dispatcher, dropping the workers' senders, meaning the workers will stop receiving,
+        // and drop their clone of the merge sender. When they drop all of them, "unbatch" and
+        // then "merge" will stop receiving, and drop the sink's sender, so the sink is done
+        // once all results have been written out. `run` then confirms that no stage is left
+        // behind, and that none failed.
+        .run(WriteTo::new(io::stdout(), write))??;
+    Ok(())
+}
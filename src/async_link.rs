@@ -0,0 +1,244 @@
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::task::{Context, Poll, Waker};
+
+use crate::cancel::CancellationToken;
+use crate::link::{Backpressure, LinkConfig, LinkMetrics, SendError};
+use crate::watchdog::{self, Activity};
+
+struct State<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receivers: usize,
+    /// Set once the pipeline is cancelled.
+    cancelled: bool,
+    /// Receivers waiting for an item.
+    receiving: Vec<Waker>,
+    /// Senders waiting for room.
+    sending: Vec<Waker>,
+}
+
+impl<T> State<T> {
+    fn wake_receivers(&mut self) {
+        self.receiving.drain(..).for_each(Waker::wake);
+    }
+
+    fn wake_senders(&mut self) {
+        self.sending.drain(..).for_each(Waker::wake);
+    }
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    config: LinkConfig,
+    metrics: Arc<LinkMetrics>,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Register `waker` to be woken up, unless it already is.
+fn register(wakers: &mut Vec<Waker>, waker: &Waker) {
+    if !wakers.iter().any(|other| other.will_wake(waker)) {
+        wakers.push(waker.clone());
+    }
+}
+
+/// Same as `link::link`, for async stages: instead of blocking, a stage waiting
+/// on a link yields to the executor until it is woken up.
+pub(crate) fn async_link<T: Send + 'static>(
+    name: String,
+    config: LinkConfig,
+    token: &CancellationToken,
+) -> (AsyncOutbound<T>, AsyncInbound<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::new(),
+            senders: 1,
+            receivers: 1,
+            cancelled: token.is_cancelled(),
+            receiving: Vec::new(),
+            sending: Vec::new(),
+        }),
+        config,
+        metrics: Arc::new(LinkMetrics::new(name, config.capacity)),
+    });
+    // Wake up both ends when cancelled, they are not polling for it.
+    let weak: Weak<Shared<T>> = Arc::downgrade(&shared);
+    token.on_cancel(move || {
+        if let Some(shared) = weak.upgrade() {
+            let mut state = shared.lock();
+            state.cancelled = true;
+            state.wake_receivers();
+            state.wake_senders();
+        }
+    });
+    (
+        AsyncOutbound {
+            shared: shared.clone(),
+        },
+        AsyncInbound { shared },
+    )
+}
+
+/// The receiving end of an async link. Cloning it adds a receiver,
+/// each item going to only one of them.
+pub(crate) struct AsyncInbound<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> AsyncInbound<T> {
+    /// Wait for a value. `None` means the stage should stop:
+    /// either upstream is gone or the pipeline has been cancelled.
+    pub(crate) async fn recv(&self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let shared = &self.shared;
+        let mut state = shared.lock();
+        if state.cancelled {
+            return Poll::Ready(None);
+        }
+        if let Some(item) = state.queue.pop_front() {
+            shared.metrics.set_depth(state.queue.len());
+            state.wake_senders();
+            watchdog::set_activity(Activity::Running);
+            return Poll::Ready(Some(item));
+        }
+        if state.senders == 0 {
+            return Poll::Ready(None);
+        }
+        register(&mut state.receiving, cx.waker());
+        watchdog::set_activity(Activity::Receiving {
+            link: shared.metrics.name().to_owned(),
+        });
+        Poll::Pending
+    }
+
+    pub(crate) fn metrics(&self) -> Arc<LinkMetrics> {
+        self.shared.metrics.clone()
+    }
+}
+
+impl<T> Clone for AsyncInbound<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().receivers += 1;
+        AsyncInbound {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for AsyncInbound<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.receivers -= 1;
+        if state.receivers == 0 {
+            // Whatever is left will never be received.
+            state.queue.clear();
+            self.shared.metrics.set_depth(0);
+            state.wake_senders();
+        }
+    }
+}
+
+/// The sending end of an async link. Cloning it adds a sender,
+/// the link closing once all of them are dropped.
+pub(crate) struct AsyncOutbound<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> AsyncOutbound<T> {
+    /// Hand a value to downstream, applying the link's backpressure policy if it is full.
+    pub(crate) async fn send(&self, item: T) -> Result<(), SendError<T>> {
+        let mut item = Some(item);
+        let mut saturated = false;
+        poll_fn(|cx| self.poll_send(cx, &mut item, &mut saturated)).await
+    }
+
+    fn poll_send(
+        &self,
+        cx: &mut Context<'_>,
+        item: &mut Option<T>,
+        saturated: &mut bool,
+    ) -> Poll<Result<(), SendError<T>>> {
+        let shared = &self.shared;
+        let metrics = &shared.metrics;
+        let mut state = shared.lock();
+        let Some(value) = item.take() else {
+            unreachable!("polled after completion");
+        };
+        if state.cancelled || state.receivers == 0 {
+            return Poll::Ready(Err(SendError::Closed(value)));
+        }
+        let full = shared
+            .config
+            .capacity
+            .is_some_and(|capacity| state.queue.len() >= capacity);
+        if full {
+            if !*saturated {
+                *saturated = true;
+                metrics.saturated();
+            }
+            match shared.config.backpressure {
+                Backpressure::Block => {
+                    register(&mut state.sending, cx.waker());
+                    *item = Some(value);
+                    watchdog::set_activity(Activity::Sending {
+                        link: metrics.name().to_owned(),
+                    });
+                    return Poll::Pending;
+                }
+                Backpressure::DropNewest => {
+                    metrics.dropped();
+                    return Poll::Ready(Ok(()));
+                }
+                Backpressure::DropOldest => {
+                    metrics.dropped();
+                    state.queue.pop_front();
+                }
+                Backpressure::Error => {
+                    metrics.dropped();
+                    return Poll::Ready(Err(SendError::Full(value)));
+                }
+                Backpressure::Disconnect => {
+                    metrics.dropped();
+                    return Poll::Ready(Err(SendError::Disconnected(value)));
+                }
+            }
+        }
+        state.queue.push_back(value);
+        metrics.sent(state.queue.len());
+        state.wake_receivers();
+        if *saturated {
+            watchdog::set_activity(Activity::Running);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> Clone for AsyncOutbound<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        AsyncOutbound {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for AsyncOutbound<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.wake_receivers();
+        }
+    }
+}
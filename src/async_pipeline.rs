@@ -0,0 +1,791 @@
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Display};
+use std::future::{poll_fn, Future};
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::pin;
+use std::sync::mpsc::{channel, Receiver, TryIter};
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant};
+
+use crate::async_link::{async_link, AsyncInbound, AsyncOutbound};
+use crate::cancel::CancellationToken;
+use crate::dispatch::{Dispatcher, WorkerLoad};
+use crate::error::{panic_message, PipelineError, StageError, StageErrorKind, StageReport};
+use crate::event;
+use crate::executor::{sleep_until, yield_now, Executor};
+use crate::fan_out::{default_workers, FanOut};
+use crate::link::{Backpressure, Envelope, LinkConfig, SendError};
+use crate::log::{self, ItemId, Level, Logger, Silent};
+use crate::metrics::Metrics;
+use crate::source::Source;
+use crate::stage::StageConfig;
+use crate::watchdog::{self, Activity, StageStatus};
+use crate::wiring::{lock, pass, reject, Route, StageCtx, Wiring};
+
+/// How long a rate limited source sleeps before checking for cancellation again.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A single step of an async pipeline, see `Stage`.
+/// Instead of blocking a thread, a stage waiting on I/O yields to the executor.
+pub trait AsyncStage<In, Out>: Send + 'static {
+    fn process(&mut self, input: In) -> impl Future<Output = Out> + Send;
+}
+
+/// Any closure returning a future of the right shape can be plugged in as an async stage,
+/// e.g. `|num| async move { num * num }`.
+impl<In, Out, F, Fut> AsyncStage<In, Out> for F
+where
+    F: FnMut(In) -> Fut + Send + 'static,
+    Fut: Future<Output = Out> + Send,
+{
+    fn process(&mut self, input: In) -> impl Future<Output = Out> + Send {
+        self(input)
+    }
+}
+
+/// Deferred wiring of everything upstream of (and including) the current stage,
+/// as in `Pipeline`.
+type AsyncBuild<T> = Box<dyn FnOnce(&AsyncWiring<'_>) -> AsyncInbound<Envelope<T>> + Send>;
+
+/// Where the items of a pipeline started with `AsyncPipeline::input` are sent once it is spawned.
+pub(crate) type InputSlot<T> = Arc<Mutex<Option<AsyncOutbound<Envelope<T>>>>>;
+
+/// The async counterpart of `Pipeline`: stages are tasks run by an `Executor`
+/// instead of threads, connected by links they wait on without blocking.
+/// Stage configs, errors, logging and metrics work the same way.
+pub struct AsyncPipeline<T> {
+    build: AsyncBuild<T>,
+    logger: Arc<dyn Logger>,
+}
+
+impl<T: Send + Debug + 'static> AsyncPipeline<T> {
+    /// Start a pipeline from a source, which is drained until it runs out of items,
+    /// until nobody downstream is listening anymore or until the pipeline is cancelled.
+    ///
+    /// The source is iterated by a task like any other stage, on a thread of the executor:
+    /// an iterator blocking until its next item is there, e.g. `Source::lines` reading
+    /// a socket, holds that thread up meanwhile, along with the tasks waiting for it.
+    /// Give the executor a thread to spare for it, or use a `Pipeline` for such sources.
+    pub fn source(config: impl Into<StageConfig>, source: Source<T>) -> Self {
+        let config = config.into();
+        let Source {
+            mut items,
+            interval,
+        } = source;
+        AsyncPipeline {
+            build: Box::new(move |wiring| {
+                let (tx, rx) = wiring.link(config.name.clone(), config.link);
+                wiring.spawn(&config, move |ctx| async move {
+                    let mut next_id = 0;
+                    let mut next_at = Instant::now();
+                    while !ctx.token.is_cancelled() {
+                        if let Some(interval) = interval {
+                            while Instant::now() < next_at && !ctx.token.is_cancelled() {
+                                sleep_until(next_at.min(Instant::now() + POLL_INTERVAL))
+                                    .await
+                                    .map_err(|source| PipelineError::Spawn {
+                                        stage: "timer".to_owned(),
+                                        source,
+                                    })?;
+                            }
+                            next_at = next_at.max(Instant::now()) + interval;
+                        }
+                        let id = ItemId(next_id);
+                        log::enter_item(Some(id));
+                        let next = ctx.metrics.track(
+                            || ctx.guard(None, || items.next()),
+                            |next| match next {
+                                Ok(Some(None)) => None,
+                                next => Some(matches!(next, Ok(Some(Some(_))))),
+                            },
+                        );
+                        let item = match next? {
+                            Some(Some(item)) => item,
+                            Some(None) => break,
+                            // The panic was reported, but there is no telling whether
+                            // an iterator that panicked would ever return an item again.
+                            None => break,
+                        };
+                        next_id += 1;
+                        event!(Level::Trace, "emitting {:?}", item);
+                        if !forward(&ctx, &tx, Envelope { id, item }).await {
+                            break;
+                        }
+                        // Sending never waits on an unbounded link,
+                        // so let the other stages have a go.
+                        yield_now().await;
+                    }
+                    Ok(())
+                });
+                rx
+            }),
+            logger: Arc::new(Silent),
+        }
+    }
+
+    /// Start a pipeline from the items sent into `slot` once it is spawned,
+    /// rather than from a source stage. The link they go through is unbounded.
+    pub(crate) fn input(name: &str, slot: InputSlot<T>) -> Self {
+        let name = name.to_owned();
+        AsyncPipeline {
+            build: Box::new(move |wiring| {
+                let (tx, rx) = wiring.link(name, LinkConfig::default());
+                *lock(&slot) = Some(tx);
+                rx
+            }),
+            logger: Arc::new(Silent),
+        }
+    }
+
+    /// Send the log events of every stage to `logger`. Nothing is logged by default.
+    pub fn logger(mut self, logger: impl Logger) -> Self {
+        self.logger = Arc::new(logger);
+        self
+    }
+
+    /// Append an async stage to the pipeline.
+    pub fn stage<U, S>(self, config: impl Into<StageConfig>, stage: S) -> AsyncPipeline<U>
+    where
+        U: Send + 'static,
+        S: AsyncStage<T, U>,
+    {
+        self.stage_with(config.into(), stage, pass::<U>())
+    }
+
+    /// Append a fallible async stage, see `Pipeline::try_stage`.
+    pub fn try_stage<U, E, S>(self, config: impl Into<StageConfig>, stage: S) -> AsyncPipeline<U>
+    where
+        U: Send + 'static,
+        E: Display + Send + 'static,
+        S: AsyncStage<T, Result<U, E>>,
+    {
+        self.stage_with(config.into(), stage, reject::<U, E>())
+    }
+
+    /// Distribute the values over several workers, each one a task with its own clone
+    /// of `stage`, see `Pipeline::fan_out`. Unlike there, the workers are set once and for all,
+    /// and the dispatcher waits for room in the link to a worker rather than give up on it:
+    /// waiting doesn't hold up a thread.
+    pub fn fan_out<U, S>(
+        self,
+        config: impl Into<StageConfig>,
+        fan_out: FanOut<T>,
+        stage: S,
+    ) -> AsyncPipeline<U>
+    where
+        U: Send + 'static,
+        S: AsyncStage<T, U> + Clone,
+    {
+        self.fan_out_with(config.into(), fan_out, stage, pass::<U>())
+    }
+
+    /// Same as `fan_out`, with fallible workers as in `try_stage`.
+    pub fn try_fan_out<U, E, S>(
+        self,
+        config: impl Into<StageConfig>,
+        fan_out: FanOut<T>,
+        stage: S,
+    ) -> AsyncPipeline<U>
+    where
+        U: Send + 'static,
+        E: Display + Send + 'static,
+        S: AsyncStage<T, Result<U, E>> + Clone,
+    {
+        self.fan_out_with(config.into(), fan_out, stage, reject::<U, E>())
+    }
+
+    fn stage_with<Out, U, S>(
+        self,
+        config: StageConfig,
+        stage: S,
+        route: Route<Out, U>,
+    ) -> AsyncPipeline<U>
+    where
+        Out: Send + 'static,
+        U: Send + 'static,
+        S: AsyncStage<T, Out>,
+    {
+        let upstream = self.build;
+        AsyncPipeline {
+            build: Box::new(move |wiring| {
+                let inbound = upstream(wiring);
+                let (tx, rx) = wiring.link(config.name.clone(), config.link);
+                spawn_worker(wiring, &config, stage, route, inbound, tx);
+                rx
+            }),
+            logger: self.logger,
+        }
+    }
+
+    fn fan_out_with<Out, U, S>(
+        self,
+        config: StageConfig,
+        fan_out: FanOut<T>,
+        stage: S,
+        route: Route<Out, U>,
+    ) -> AsyncPipeline<U>
+    where
+        Out: Send + 'static,
+        U: Send + 'static,
+        S: AsyncStage<T, Out> + Clone,
+    {
+        let upstream = self.build;
+        AsyncPipeline {
+            build: Box::new(move |wiring| {
+                let inbound = upstream(wiring);
+                spawn_fan_out(wiring, &config, fan_out, stage, route, inbound)
+            }),
+            logger: self.logger,
+        }
+    }
+
+    /// Spawn every stage on `executor` and return the handle to the running pipeline.
+    pub fn spawn(self, executor: &dyn Executor) -> AsyncPipelineHandle<T> {
+        let (errors_tx, errors_rx) = channel();
+        let wiring = AsyncWiring {
+            wiring: Wiring::new(CancellationToken::new(), errors_tx, self.logger),
+            executor,
+            tasks: Mutex::default(),
+        };
+        let output = (self.build)(&wiring);
+        AsyncPipelineHandle {
+            output: Some(output),
+            errors: errors_rx,
+            tasks: wiring
+                .tasks
+                .into_inner()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+            wiring: wiring.wiring,
+        }
+    }
+}
+
+/// Owns every task of a running async pipeline, see `PipelineHandle`.
+pub struct AsyncPipelineHandle<T> {
+    output: Option<AsyncInbound<Envelope<T>>>,
+    errors: Receiver<StageError>,
+    tasks: Vec<(String, Completion)>,
+    wiring: Wiring,
+}
+
+impl<T> AsyncPipelineHandle<T> {
+    /// Wait for the next output value, `None` once the last stage has stopped.
+    pub async fn recv(&mut self) -> Option<T> {
+        let envelope = self.output.as_ref()?.recv().await?;
+        Some(envelope.item)
+    }
+
+    /// The errors reported by the stages so far, e.g. panics that were caught.
+    pub fn errors(&self) -> TryIter<'_, StageError> {
+        self.errors.try_iter()
+    }
+
+    /// The live metrics of every stage and link.
+    pub fn metrics(&self) -> Metrics {
+        self.wiring.metrics()
+    }
+
+    /// A token that stops this pipeline when cancelled, usable from any thread.
+    pub fn token(&self) -> CancellationToken {
+        self.wiring.token.clone()
+    }
+
+    /// Ask every stage to stop, without waiting for them to do so.
+    pub fn cancel(&self) {
+        self.wiring.token.cancel();
+    }
+
+    /// What every stage is doing right now, see `PipelineHandle::status`.
+    pub fn status(&self) -> Vec<StageStatus> {
+        lock(&self.wiring.probes)
+            .iter()
+            .map(|probe| probe.status())
+            .collect()
+    }
+
+    /// The stages whose task is not done yet, in the order they were spawned.
+    pub fn running(&self) -> Vec<String> {
+        self.tasks
+            .iter()
+            .filter(|(_, completion)| !completion.is_done())
+            .map(|(stage, _)| stage.clone())
+            .collect()
+    }
+
+    /// Wait for every stage task to be done, in the order the stages were spawned.
+    pub async fn join(self) -> Vec<StageReport> {
+        // Nobody is going to read the output anymore.
+        drop(self.output);
+        let mut reports = Vec::new();
+        for (stage, completion) in self.tasks {
+            let result = completion.wait().await;
+            reports.push(StageReport { stage, result });
+        }
+        reports
+    }
+
+    /// Cancel the pipeline and wait for all its stages to be done.
+    pub async fn shutdown(self) -> Vec<StageReport> {
+        self.cancel();
+        self.join().await
+    }
+}
+
+/// What async stages get wired with while a pipeline is being spawned.
+struct AsyncWiring<'a> {
+    wiring: Wiring,
+    executor: &'a dyn Executor,
+    tasks: Mutex<Vec<(String, Completion)>>,
+}
+
+impl AsyncWiring<'_> {
+    fn link<T: Send + 'static>(
+        &self,
+        name: String,
+        config: LinkConfig,
+    ) -> (AsyncOutbound<T>, AsyncInbound<T>) {
+        let (tx, rx) = async_link(name, config, &self.wiring.token);
+        lock(&self.wiring.links).push(rx.metrics());
+        (tx, rx)
+    }
+
+    /// Run the future returned by `body` as a task of its own, within the stage's log span.
+    /// A panic escaping it is reported and fails the whole pipeline.
+    fn spawn<F, Fut>(&self, config: &StageConfig, body: F)
+    where
+        F: FnOnce(Arc<StageCtx>) -> Fut,
+        Fut: Future<Output = Result<(), PipelineError>> + Send + 'static,
+    {
+        let ctx = Arc::new(self.wiring.ctx(config));
+        let state = Arc::new(Mutex::new(TaskState::default()));
+        let done = Completer {
+            stage: config.name.clone(),
+            state: state.clone(),
+            completed: false,
+        };
+        let task = run(ctx.clone(), self.wiring.logger(), body(ctx), done);
+        self.executor.spawn(Box::pin(task));
+        lock(&self.tasks).push((config.name.clone(), Completion { state }));
+    }
+}
+
+/// Poll `body` within the stage's log span until it is done, then report how it ended.
+async fn run<Fut>(ctx: Arc<StageCtx>, logger: Arc<dyn Logger>, body: Fut, done: Completer)
+where
+    Fut: Future<Output = Result<(), PipelineError>>,
+{
+    let mut body = pin!(body);
+    let mut started = false;
+    // The item being processed, kept aside while other tasks run on the thread.
+    let mut item = None;
+    let result = poll_fn(|cx| {
+        log::enter_stage(&ctx.name, logger.clone());
+        watchdog::attach(Some(ctx.probe.clone()));
+        log::enter_item(item);
+        if !started {
+            started = true;
+            event!(Level::Debug, "started");
+        }
+        let poll = match panic::catch_unwind(AssertUnwindSafe(|| body.as_mut().poll(cx))) {
+            Ok(poll) => poll,
+            Err(payload) => Poll::Ready(Err(ctx.fail(None, panic_message(payload.as_ref())))),
+        };
+        if let Poll::Ready(result) = &poll {
+            log::enter_item(None);
+            match result {
+                Ok(()) => event!(Level::Debug, "stopped"),
+                Err(err) => event!(Level::Error, "stopped: {}", err),
+            }
+            ctx.probe.set(Activity::Stopped);
+        }
+        watchdog::attach(None);
+        item = log::leave_stage();
+        poll
+    })
+    .await;
+    done.complete(result);
+}
+
+/// Spawn the workers of a fan-out stage and what feeds them, returning the link
+/// carrying the merged outputs of the workers.
+fn spawn_fan_out<T, Out, U, S>(
+    wiring: &AsyncWiring<'_>,
+    config: &StageConfig,
+    fan_out: FanOut<T>,
+    stage: S,
+    route: Route<Out, U>,
+    inbound: AsyncInbound<Envelope<T>>,
+) -> AsyncInbound<Envelope<U>>
+where
+    T: Send + Debug + 'static,
+    Out: Send + 'static,
+    U: Send + 'static,
+    S: AsyncStage<T, Out> + Clone,
+{
+    let FanOut {
+        workers,
+        dispatcher,
+        ordered,
+    } = fan_out;
+    let workers = workers.unwrap_or_else(default_workers);
+    let worker_config = |id| {
+        let mut worker_config = config.clone();
+        worker_config.name = format!("{}-{}", config.name, id);
+        worker_config
+    };
+    let link = LinkConfig {
+        backpressure: Backpressure::Block,
+        ..config.link
+    };
+    let Some(reorder_buffer) = ordered else {
+        let (tx, rx) = wiring.link(config.name.clone(), config.link);
+        // Workers sharing a queue take their items straight from upstream.
+        let inbounds = if dispatcher.shares_queue() {
+            vec![inbound; workers]
+        } else {
+            dispatch(wiring, config, dispatcher, workers, link, inbound, None)
+        };
+        for (id, inbound) in inbounds.into_iter().enumerate() {
+            let (stage, tx) = (stage.clone(), tx.clone());
+            spawn_worker(wiring, &worker_config(id), stage, route, inbound, tx);
+        }
+        return rx;
+    };
+
+    // One permit per item between the dispatcher and the reorder buffer,
+    // so that no more than `reorder_buffer` items are given to the workers at once.
+    let permits = LinkConfig {
+        capacity: Some(reorder_buffer),
+        backpressure: Backpressure::Block,
+    };
+    let (permits_tx, permits_rx) = wiring.link(format!("{}-reorder", config.name), permits);
+    let inbounds = dispatch(
+        wiring,
+        config,
+        dispatcher,
+        workers,
+        link,
+        inbound,
+        Some(permits_tx),
+    );
+    let (merge_tx, merge_rx) = wiring.link(config.name.clone(), link);
+    for (id, inbound) in inbounds.into_iter().enumerate() {
+        let (stage, tx) = (stage.clone(), merge_tx.clone());
+        spawn_ordered_worker(wiring, &worker_config(id), stage, route, inbound, tx);
+    }
+    drop(merge_tx);
+
+    let mut merge_config = config.clone();
+    merge_config.name = format!("{}-merge", config.name);
+    let (tx, rx) = wiring.link(merge_config.name.clone(), config.link);
+    wiring.spawn(&merge_config, move |ctx| async move {
+        let mut buffer = BTreeMap::new();
+        let mut next = 0;
+        'merge: while let Some((seq, out)) = merge_rx.recv().await {
+            buffer.insert(seq, out);
+            while let Some(out) = buffer.remove(&next) {
+                next += 1;
+                // Let one more item in.
+                permits_rx.recv().await;
+                if let Some(out) = out {
+                    log::enter_item(Some(out.id));
+                    if !forward(&ctx, &tx, out).await {
+                        break 'merge;
+                    }
+                }
+            }
+        }
+        Ok(())
+    });
+    rx
+}
+
+/// What the dispatcher of a fan-out stage gives the workers: the items themselves,
+/// or numbered items for an ordered fan-out.
+trait Dispatched<T>: Send + 'static {
+    fn new(seq: u64, envelope: Envelope<T>) -> Self;
+
+    /// The item the dispatcher looks at to pick a worker.
+    fn item(&self) -> &T;
+}
+
+impl<T: Send + 'static> Dispatched<T> for Envelope<T> {
+    fn new(_: u64, envelope: Envelope<T>) -> Self {
+        envelope
+    }
+
+    fn item(&self) -> &T {
+        &self.item
+    }
+}
+
+impl<T: Send + 'static> Dispatched<T> for (u64, Envelope<T>) {
+    fn new(seq: u64, envelope: Envelope<T>) -> Self {
+        (seq, envelope)
+    }
+
+    fn item(&self) -> &T {
+        &self.1.item
+    }
+}
+
+/// Spawn the dispatcher of a fan-out stage, giving the items from `inbound` to the workers
+/// picked by `dispatcher`, once `permits` has room for one more if any. Returns the link
+/// each worker receives from.
+fn dispatch<T, W>(
+    wiring: &AsyncWiring<'_>,
+    config: &StageConfig,
+    mut dispatcher: Box<dyn Dispatcher<T>>,
+    workers: usize,
+    link: LinkConfig,
+    inbound: AsyncInbound<Envelope<T>>,
+    permits: Option<AsyncOutbound<()>>,
+) -> Vec<AsyncInbound<W>>
+where
+    T: Send + 'static,
+    W: Dispatched<T>,
+{
+    let mut dispatch_config = config.clone();
+    dispatch_config.name = format!("{}-dispatch", config.name);
+    let shares_queue = dispatcher.shares_queue();
+    let mut links = Vec::new();
+    let mut inbounds = Vec::new();
+    if shares_queue {
+        let link_name = format!("{}->{}", dispatch_config.name, config.name);
+        let (tx, rx) = wiring.link(link_name, link);
+        links.push((0, tx, rx.metrics()));
+        inbounds = vec![rx; workers];
+    } else {
+        for id in 0..workers {
+            let link_name = format!("{}->{}-{}", dispatch_config.name, config.name, id);
+            let (tx, rx) = wiring.link(link_name, link);
+            links.push((id, tx, rx.metrics()));
+            inbounds.push(rx);
+        }
+    }
+    wiring.spawn(&dispatch_config, move |_| async move {
+        let mut seq = 0;
+        let mut loads = Vec::new();
+        'items: while let Some(envelope) = inbound.recv().await {
+            if let Some(permits) = &permits {
+                if permits.send(()).await.is_err() {
+                    break;
+                }
+            }
+            let mut item = W::new(seq, envelope);
+            seq += 1;
+            // Hand the item to the worker picked by the dispatcher,
+            // forgetting about the workers that have stopped receiving.
+            loop {
+                if links.is_empty() {
+                    break 'items;
+                }
+                let selected = if shares_queue {
+                    0
+                } else {
+                    loads.clear();
+                    loads.extend(links.iter().map(|(id, _, metrics)| WorkerLoad {
+                        id: *id,
+                        depth: metrics.depth(),
+                    }));
+                    dispatcher.select(item.item(), &loads).min(links.len() - 1)
+                };
+                match links[selected].1.send(item).await {
+                    Ok(()) => break,
+                    Err(SendError::Closed(rejected)) => {
+                        links.remove(selected);
+                        item = rejected;
+                    }
+                    Err(SendError::Full(_) | SendError::Disconnected(_)) => {
+                        unreachable!("the links to the workers wait for room")
+                    }
+                }
+            }
+        }
+        Ok(())
+    });
+    inbounds
+}
+
+fn spawn_worker<In, Out, U, S>(
+    wiring: &AsyncWiring<'_>,
+    config: &StageConfig,
+    mut stage: S,
+    route: Route<Out, U>,
+    inbound: AsyncInbound<Envelope<In>>,
+    outbound: AsyncOutbound<Envelope<U>>,
+) where
+    In: Send + Debug + 'static,
+    Out: Send + 'static,
+    U: Send + 'static,
+    S: AsyncStage<In, Out>,
+{
+    wiring.spawn(config, move |ctx| async move {
+        let mut repr = String::new();
+        while let Some(Envelope { id, item }) = inbound.recv().await {
+            log::enter_item(Some(id));
+            let Some(item) = process(&ctx, &mut stage, route, &mut repr, item).await? else {
+                continue;
+            };
+            if !forward(&ctx, &outbound, Envelope { id, item }).await {
+                break;
+            }
+        }
+        Ok(())
+    });
+}
+
+/// Same as `spawn_worker`, for numbered items. Every number makes it downstream,
+/// with `None` in place of the items that were dropped.
+fn spawn_ordered_worker<In, Out, U, S>(
+    wiring: &AsyncWiring<'_>,
+    config: &StageConfig,
+    mut stage: S,
+    route: Route<Out, U>,
+    inbound: AsyncInbound<(u64, Envelope<In>)>,
+    outbound: AsyncOutbound<(u64, Option<Envelope<U>>)>,
+) where
+    In: Send + Debug + 'static,
+    Out: Send + 'static,
+    U: Send + 'static,
+    S: AsyncStage<In, Out>,
+{
+    wiring.spawn(config, move |ctx| async move {
+        let mut repr = String::new();
+        while let Some((seq, Envelope { id, item })) = inbound.recv().await {
+            log::enter_item(Some(id));
+            let out = process(&ctx, &mut stage, route, &mut repr, item).await?;
+            let out = out.map(|item| Envelope { id, item });
+            if !forward(&ctx, &outbound, (seq, out)).await {
+                break;
+            }
+        }
+        Ok(())
+    });
+}
+
+/// Run `item` through `stage` then `route`. `Ok(None)` if it was dropped on the way.
+async fn process<In, Out, U, S>(
+    ctx: &StageCtx,
+    stage: &mut S,
+    route: Route<Out, U>,
+    repr: &mut String,
+    item: In,
+) -> Result<Option<U>, PipelineError>
+where
+    In: Debug,
+    S: AsyncStage<In, Out>,
+{
+    event!(Level::Trace, "processing {:?}", item);
+    // The item is moved into the stage, so a panic can only tell what was described beforehand.
+    let described = route.describe(repr, &item, ctx.describe_items);
+    let repr: &str = repr;
+    let started = ctx.metrics.start();
+    let out = guard(ctx, described.then_some(repr), stage.process(item)).await;
+    let out = out.map(|out| out.and_then(|out| route.apply(ctx, repr, out)));
+    ctx.metrics
+        .finish(started, Some(matches!(out, Ok(Some(_)))));
+    out
+}
+
+/// Same as `StageCtx::guard`, for a future: poll it to completion,
+/// isolating any panic according to the stage's panic policy.
+async fn guard<F: Future>(
+    ctx: &StageCtx,
+    item: Option<&str>,
+    future: F,
+) -> Result<Option<F::Output>, PipelineError> {
+    let mut future = pin!(future);
+    let result =
+        poll_fn(
+            |cx| match panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+                Ok(Poll::Pending) => Poll::Pending,
+                Ok(Poll::Ready(out)) => Poll::Ready(Ok(out)),
+                Err(payload) => Poll::Ready(Err(payload)),
+            },
+        )
+        .await;
+    ctx.caught(item, result)
+}
+
+/// Same as `StageCtx::forward`, waiting for room instead of blocking.
+async fn forward<T>(ctx: &StageCtx, outbound: &AsyncOutbound<T>, item: T) -> bool {
+    match outbound.send(item).await {
+        Ok(()) => true,
+        Err(SendError::Full(_)) => {
+            ctx.report(None, StageErrorKind::LinkFull);
+            true
+        }
+        Err(SendError::Disconnected(_)) => {
+            ctx.report(None, StageErrorKind::Disconnected);
+            false
+        }
+        Err(SendError::Closed(_)) => false,
+    }
+}
+
+#[derive(Default)]
+struct TaskState {
+    result: Option<Result<(), PipelineError>>,
+    /// Whoever is waiting for the result.
+    waiter: Option<Waker>,
+}
+
+/// Hands the result of a stage task over to `join`.
+struct Completer {
+    stage: String,
+    state: Arc<Mutex<TaskState>>,
+    completed: bool,
+}
+
+impl Completer {
+    fn complete(mut self, result: Result<(), PipelineError>) {
+        self.completed = true;
+        self.set(result);
+    }
+
+    fn set(&self, result: Result<(), PipelineError>) {
+        let mut state = lock(&self.state);
+        state.result = Some(result);
+        if let Some(waiter) = state.waiter.take() {
+            waiter.wake();
+        }
+    }
+}
+
+impl Drop for Completer {
+    fn drop(&mut self) {
+        // The executor dropped the task before it was done.
+        if !self.completed {
+            self.set(Err(PipelineError::Abandoned {
+                stage: self.stage.clone(),
+            }));
+        }
+    }
+}
+
+/// The result of a stage task, once it is done.
+struct Completion {
+    state: Arc<Mutex<TaskState>>,
+}
+
+impl Completion {
+    fn is_done(&self) -> bool {
+        lock(&self.state).result.is_some()
+    }
+
+    async fn wait(self) -> Result<(), PipelineError> {
+        poll_fn(|cx| {
+            let mut state = lock(&self.state);
+            match state.result.take() {
+                Some(result) => Poll::Ready(result),
+                None => {
+                    state.waiter = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
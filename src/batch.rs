@@ -0,0 +1,189 @@
+use std::fmt::Debug;
+use std::mem;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
+
+use crate::error::PipelineError;
+use crate::event;
+use crate::link::{Envelope, Inbound, Outbound};
+use crate::log::{self, ItemId, Level};
+use crate::stage::StageConfig;
+use crate::wiring::{StageCtx, Wiring};
+
+/// Measures an item, for `Batch::max_bytes`.
+type Size<T> = Box<dyn Fn(&T) -> usize + Send>;
+
+/// How a batching stage groups items together, see `Pipeline::batch`.
+/// A batch is sent downstream as soon as it reaches any of its limits,
+/// and whatever is left once upstream is done goes out as a last, smaller batch.
+/// Without any limit, every item ends up in a single batch.
+pub struct Batch<T> {
+    max_items: Option<usize>,
+    max_bytes: Option<(usize, Size<T>)>,
+    max_latency: Option<Duration>,
+}
+
+impl<T> Batch<T> {
+    pub fn new() -> Self {
+        Batch {
+            max_items: None,
+            max_bytes: None,
+            max_latency: None,
+        }
+    }
+
+    /// Send a batch once it holds `max_items` items.
+    pub fn max_items(mut self, max_items: usize) -> Self {
+        assert!(max_items > 0, "a batch holds at least one item");
+        self.max_items = Some(max_items);
+        self
+    }
+
+    /// Keep batches within `max_bytes`, as measured by `size`, e.g. `|frame: &Vec<u8>| frame.len()`.
+    /// An item that would take its batch over the limit starts the next one,
+    /// and an item bigger than the limit goes out in a batch of its own.
+    pub fn max_bytes(
+        mut self,
+        max_bytes: usize,
+        size: impl Fn(&T) -> usize + Send + 'static,
+    ) -> Self {
+        assert!(max_bytes > 0, "a batch holds at least one byte");
+        self.max_bytes = Some((max_bytes, Box::new(size)));
+        self
+    }
+
+    /// Send a batch at most `max_latency` after its first item came in, however small it is,
+    /// so that items don't wait forever for the batch to fill up when traffic is low.
+    pub fn max_latency(mut self, max_latency: Duration) -> Self {
+        self.max_latency = Some(max_latency);
+        self
+    }
+}
+
+impl<T> Default for Batch<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The batch being filled up by a batching stage.
+struct Pending<T> {
+    items: Vec<T>,
+    /// The id of the first item, which the batch goes by downstream.
+    id: ItemId,
+    bytes: usize,
+    /// When the batch has to go out, however small it is.
+    deadline: Option<Instant>,
+}
+
+impl<T> Pending<T> {
+    /// Hand the batch over to downstream, starting a new one.
+    /// Returns `false` once the stage should stop, downstream being gone.
+    fn flush(&mut self, ctx: &StageCtx, outbound: &Outbound<Envelope<Vec<T>>>) -> bool {
+        self.bytes = 0;
+        self.deadline = None;
+        if self.items.is_empty() {
+            return true;
+        }
+        let items = mem::take(&mut self.items);
+        log::enter_item(Some(self.id));
+        event!(Level::Trace, "emitting a batch of {}", items.len());
+        ctx.forward(
+            outbound,
+            Envelope {
+                id: self.id,
+                item: items,
+            },
+        )
+    }
+}
+
+pub(crate) fn spawn_batcher<T>(
+    wiring: &Wiring,
+    config: &StageConfig,
+    batch: Batch<T>,
+    inbound: Inbound<Envelope<T>>,
+    outbound: Outbound<Envelope<Vec<T>>>,
+) -> Result<(), PipelineError>
+where
+    T: Send + Debug + 'static,
+{
+    wiring.spawn(config, move |ctx| {
+        let mut pending = Pending {
+            items: Vec::new(),
+            id: ItemId(0),
+            bytes: 0,
+            deadline: None,
+        };
+        loop {
+            let Envelope { id, item } = match inbound.recv_deadline(pending.deadline) {
+                Ok(envelope) => envelope,
+                Err(RecvTimeoutError::Timeout) => {
+                    if !pending.flush(ctx, &outbound) {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            log::enter_item(Some(id));
+            let started = ctx.metrics.start();
+            let bytes = match &batch.max_bytes {
+                Some((max_bytes, size)) => {
+                    let bytes = size(&item);
+                    if pending.bytes + bytes > *max_bytes && !pending.flush(ctx, &outbound) {
+                        return Ok(());
+                    }
+                    bytes
+                }
+                None => 0,
+            };
+            if pending.items.is_empty() {
+                pending.id = id;
+                pending.deadline = batch.max_latency.map(|latency| Instant::now() + latency);
+            }
+            pending.items.push(item);
+            pending.bytes += bytes;
+            ctx.metrics.finish(started, Some(true));
+            let full = batch
+                .max_items
+                .is_some_and(|max_items| pending.items.len() >= max_items)
+                || batch
+                    .max_bytes
+                    .as_ref()
+                    .is_some_and(|(max_bytes, _)| pending.bytes >= *max_bytes);
+            if full && !pending.flush(ctx, &outbound) {
+                return Ok(());
+            }
+        }
+        // Upstream is done: the last batch goes out as it is, unless the pipeline was cancelled.
+        if !ctx.token.is_cancelled() {
+            pending.flush(ctx, &outbound);
+        }
+        Ok(())
+    })
+}
+
+/// Send the items of every batch downstream one by one, each going by the id of its batch.
+pub(crate) fn spawn_unbatcher<T>(
+    wiring: &Wiring,
+    config: &StageConfig,
+    inbound: Inbound<Envelope<Vec<T>>>,
+    outbound: Outbound<Envelope<T>>,
+) -> Result<(), PipelineError>
+where
+    T: Send + Debug + 'static,
+{
+    wiring.spawn(config, move |ctx| {
+        while let Some(Envelope { id, item: items }) = inbound.recv() {
+            log::enter_item(Some(id));
+            event!(Level::Trace, "splitting a batch of {}", items.len());
+            for item in items {
+                if !ctx.forward(&outbound, Envelope { id, item }) {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    })
+}
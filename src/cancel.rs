@@ -0,0 +1,80 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::wiring::lock;
+
+/// How long `sleep_until` sleeps before checking for cancellation again.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Tells every stage of a running pipeline to stop.
+/// Cloning the token is cheap and all the clones observe the same cancellation,
+/// so it can be handed to whichever thread decides when the pipeline is done.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    /// Called once cancelled, to wake up whoever isn't polling for it.
+    on_cancel: Mutex<Vec<Box<dyn Fn() + Send + Sync>>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request every stage to stop. Stages notice it on their next send or receive.
+    pub fn cancel(&self) {
+        if self.inner.cancelled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let callbacks = std::mem::take(&mut *lock(&self.inner.on_cancel));
+        for callback in callbacks {
+            callback();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Call `callback` once cancelled, right away if already cancelled.
+    pub(crate) fn on_cancel(&self, callback: impl Fn() + Send + Sync + 'static) {
+        let mut callbacks = lock(&self.inner.on_cancel);
+        if self.is_cancelled() {
+            drop(callbacks);
+            callback();
+        } else {
+            callbacks.push(Box::new(callback));
+        }
+    }
+
+    /// Sleep until `deadline`, waking up early if cancelled.
+    /// Returns `false` if cancelled.
+    pub fn sleep_until(&self, deadline: Instant) -> bool {
+        loop {
+            if self.is_cancelled() {
+                return false;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return true;
+            }
+            thread::sleep((deadline - now).min(POLL_INTERVAL));
+        }
+    }
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hash, Hasher};
+
+/// What a dispatcher knows about a worker of a fan-out stage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WorkerLoad {
+    /// Identifies the worker for as long as it lives, e.g. `3` for `square-3`.
+    pub id: usize,
+    /// Items waiting in the worker's inbound link.
+    pub depth: usize,
+}
+
+/// Decides which worker of a fan-out stage gets each item.
+pub trait Dispatcher<T>: Send + 'static {
+    /// Pick the worker to hand `item` to, as an index into `workers`, which is never empty.
+    fn select(&mut self, item: &T, workers: &[WorkerLoad]) -> usize;
+
+    /// Whether the workers should rather share a single queue, each taking the next item
+    /// as soon as it is done with the previous one. `select` is never called if so.
+    /// See `SharedQueue`.
+    fn shares_queue(&self) -> bool {
+        false
+    }
+}
+
+/// Cycle through the workers.
+#[derive(Clone, Debug, Default)]
+pub struct RoundRobin {
+    next: usize,
+}
+
+impl<T> Dispatcher<T> for RoundRobin {
+    fn select(&mut self, _: &T, workers: &[WorkerLoad]) -> usize {
+        let selected = self.next % workers.len();
+        self.next = selected + 1;
+        selected
+    }
+}
+
+/// Give each item to the worker with the fewest items waiting.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LeastLoaded;
+
+impl<T> Dispatcher<T> for LeastLoaded {
+    fn select(&mut self, _: &T, workers: &[WorkerLoad]) -> usize {
+        workers
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, worker)| worker.depth)
+            .map_or(0, |(i, _)| i)
+    }
+}
+
+/// Give each item to a worker picked at random.
+#[derive(Clone, Debug)]
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    /// Always pick the same sequence of workers, given the same workers.
+    pub fn with_seed(seed: u64) -> Self {
+        // xorshift gets stuck on 0.
+        Random { state: seed | 1 }
+    }
+
+    /// xorshift64*, plenty for spreading load.
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+impl Default for Random {
+    fn default() -> Self {
+        Random::with_seed(RandomState::new().build_hasher().finish())
+    }
+}
+
+impl<T> Dispatcher<T> for Random {
+    fn select(&mut self, _: &T, workers: &[WorkerLoad]) -> usize {
+        (self.next() % workers.len() as u64) as usize
+    }
+}
+
+/// Always give the items with the same key to the same worker, e.g. the frames of a session.
+/// Workers are placed on a hash ring, so adding or retiring one only moves
+/// the keys of its neighbours on the ring.
+pub struct ConsistentHash<F> {
+    key: F,
+    /// Sorted by hash: each worker appears `VIRTUAL_NODES` times, spread over the ring.
+    ring: Vec<(u64, usize)>,
+    /// The workers the ring was built for.
+    workers: Vec<usize>,
+}
+
+impl<F> ConsistentHash<F> {
+    const VIRTUAL_NODES: u64 = 64;
+
+    pub fn new(key: F) -> Self {
+        ConsistentHash {
+            key,
+            ring: Vec::new(),
+            workers: Vec::new(),
+        }
+    }
+
+    fn rebuild(&mut self, workers: &[WorkerLoad]) {
+        self.workers = workers.iter().map(|worker| worker.id).collect();
+        self.ring = self
+            .workers
+            .iter()
+            .flat_map(|&id| (0..Self::VIRTUAL_NODES).map(move |node| (hash(&(id, node)), id)))
+            .collect();
+        self.ring.sort_unstable();
+    }
+}
+
+impl<T, K, F> Dispatcher<T> for ConsistentHash<F>
+where
+    K: Hash,
+    F: Fn(&T) -> K + Send + 'static,
+{
+    fn select(&mut self, item: &T, workers: &[WorkerLoad]) -> usize {
+        if !workers
+            .iter()
+            .map(|worker| worker.id)
+            .eq(self.workers.iter().copied())
+        {
+            self.rebuild(workers);
+        }
+        let key = hash(&(self.key)(item));
+        // The first worker clockwise from the key, wrapping around the ring.
+        let node = self.ring.partition_point(|&(point, _)| point < key);
+        let (_, id) = self.ring[node % self.ring.len()];
+        workers
+            .iter()
+            .position(|worker| worker.id == id)
+            .unwrap_or(0)
+    }
+}
+
+/// Let the workers take items from a single shared queue, so an idle worker
+/// picks up work as soon as there is some instead of waiting behind a busy one.
+///
+/// This is not work stealing: there are no per-worker queues for idle workers to steal from,
+/// the one queue being all the workers take from. It balances the load much the same way,
+/// with every worker locking that queue for each item rather than only when idle.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SharedQueue;
+
+impl<T> Dispatcher<T> for SharedQueue {
+    fn select(&mut self, _: &T, _: &[WorkerLoad]) -> usize {
+        0
+    }
+
+    fn shares_queue(&self) -> bool {
+        true
+    }
+}
+
+/// Deterministic, unlike `RandomState`, so keys map to the same worker from one run to the next.
+fn hash<H: Hash>(value: &H) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Workers numbered from 0, with these many items waiting.
+    fn workers(depths: &[usize]) -> Vec<WorkerLoad> {
+        depths
+            .iter()
+            .enumerate()
+            .map(|(id, &depth)| WorkerLoad { id, depth })
+            .collect()
+    }
+
+    /// The id of the worker `dispatcher` picks for each item.
+    fn picks<T, D: Dispatcher<T>>(
+        dispatcher: &mut D,
+        items: &[T],
+        workers: &[WorkerLoad],
+    ) -> Vec<usize> {
+        items
+            .iter()
+            .map(|item| workers[dispatcher.select(item, workers)].id)
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_cycles_through_the_workers() {
+        let mut round_robin = RoundRobin::default();
+        assert_eq!(
+            picks(&mut round_robin, &[(); 7], &workers(&[0; 3])),
+            [0, 1, 2, 0, 1, 2, 0]
+        );
+        // Picks up where it left off when a worker is retired.
+        assert_eq!(
+            picks(&mut round_robin, &[(); 3], &workers(&[0; 2])),
+            [1, 0, 1]
+        );
+    }
+
+    #[test]
+    fn least_loaded_picks_the_shallowest_queue() {
+        let mut least_loaded = LeastLoaded;
+        assert_eq!(picks(&mut least_loaded, &[()], &workers(&[3, 1, 2])), [1]);
+        assert_eq!(picks(&mut least_loaded, &[()], &workers(&[4, 5, 0])), [2]);
+        // Ties go to the first worker.
+        assert_eq!(picks(&mut least_loaded, &[()], &workers(&[2, 2, 2])), [0]);
+    }
+
+    #[test]
+    fn consistent_hash_keeps_keys_on_their_worker() {
+        let keys: Vec<u32> = (0..1000).collect();
+        let mut hash = ConsistentHash::new(|key: &u32| *key);
+        let four = workers(&[0; 4]);
+        let before = picks(&mut hash, &keys, &four);
+        assert_eq!(picks(&mut hash, &keys, &four), before);
+        for id in 0..4 {
+            assert!(before.contains(&id), "worker {} gets no key", id);
+        }
+
+        // Retiring a worker only moves its own keys.
+        let three = &four[..3];
+        let after = picks(&mut hash, &keys, three);
+        for (was, is) in before.iter().zip(&after) {
+            if *was == 3 {
+                assert_ne!(*is, 3);
+            } else {
+                assert_eq!(is, was);
+            }
+        }
+
+        // Adding one only moves keys to it.
+        let mut five = four.clone();
+        five.push(WorkerLoad { id: 4, depth: 0 });
+        let after = picks(&mut hash, &keys, &five);
+        assert!(after.contains(&4));
+        for (was, is) in before.iter().zip(&after) {
+            assert!(is == was || *is == 4);
+        }
+    }
+
+    #[test]
+    fn random_spreads_items_evenly() {
+        let four = workers(&[0; 4]);
+        let items = [(); 4000];
+        let picked = picks(&mut Random::with_seed(7), &items, &four);
+        assert_eq!(picks(&mut Random::with_seed(7), &items, &four), picked);
+        for id in 0..4 {
+            let count = picked.iter().filter(|&&picked| picked == id).count();
+            assert!(
+                (800..1200).contains(&count),
+                "worker {} got {} items",
+                id,
+                count
+            );
+        }
+    }
+}
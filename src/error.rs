@@ -0,0 +1,181 @@
+use std::any::Any;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use crate::log::ItemId;
+
+/// Everything that can go wrong with a pipeline as a whole.
+#[derive(Debug)]
+pub enum PipelineError {
+    /// The OS refused to start the thread of a stage.
+    Spawn { stage: String, source: io::Error },
+    /// A stage thread panicked.
+    Panicked { stage: String, message: String },
+    /// Workers can't be added to a fan-out stage that has stopped dispatching.
+    PoolStopped { stage: String },
+    /// The executor of an async pipeline dropped the task of a stage before it was done.
+    Abandoned { stage: String },
+    /// The pipeline was cancelled before its sink was done.
+    Cancelled,
+    /// A topology was wired wrong, and none of its stages were spawned.
+    Topology(TopologyError),
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PipelineError::Spawn { stage, source } => {
+                write!(f, "failed to spawn stage {:?}: {}", stage, source)
+            }
+            PipelineError::Panicked { stage, message } => {
+                write!(f, "stage {:?} panicked: {}", stage, message)
+            }
+            PipelineError::PoolStopped { stage } => {
+                write!(f, "fan-out stage {:?} has stopped", stage)
+            }
+            PipelineError::Abandoned { stage } => {
+                write!(f, "stage {:?} was dropped by its executor", stage)
+            }
+            PipelineError::Cancelled => f.write_str("the pipeline was cancelled"),
+            PipelineError::Topology(err) => write!(f, "invalid topology: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PipelineError::Spawn { source, .. } => Some(source),
+            PipelineError::Topology(err) => Some(err),
+            PipelineError::Panicked { .. }
+            | PipelineError::PoolStopped { .. }
+            | PipelineError::Abandoned { .. }
+            | PipelineError::Cancelled => None,
+        }
+    }
+}
+
+/// What is wrong with a topology, found before any of its stages is spawned.
+/// Ports are named after their node, e.g. `zip.left` for one of the inputs of a zip.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TopologyError {
+    /// Two nodes have the same name.
+    DuplicateNode(String),
+    /// Nothing is connected to an input, which would never receive anything.
+    UnconnectedInput(String),
+    /// An output is connected to nothing, so whatever comes out of it would be lost.
+    UnconnectedOutput(String),
+    /// A branch comes after one taking every item, and would never get any.
+    Unreachable { from: String, to: String },
+    /// The nodes of a cycle, the first one repeated at the end.
+    /// Stages waiting on each other in a loop could never stop.
+    Cycle(Vec<String>),
+}
+
+impl fmt::Display for TopologyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopologyError::DuplicateNode(node) => write!(f, "two nodes are called {:?}", node),
+            TopologyError::UnconnectedInput(port) => {
+                write!(f, "input {:?} is not connected", port)
+            }
+            TopologyError::UnconnectedOutput(port) => {
+                write!(f, "output {:?} is not connected", port)
+            }
+            TopologyError::Unreachable { from, to } => write!(
+                f,
+                "{:?} never gets anything from {:?}, an earlier branch takes it all",
+                to, from
+            ),
+            TopologyError::Cycle(nodes) => write!(f, "cycle {}", nodes.join(" -> ")),
+        }
+    }
+}
+
+impl std::error::Error for TopologyError {}
+
+/// An error that happened within a stage while the pipeline was running,
+/// as reported on the pipeline's error channel.
+#[derive(Clone, Debug)]
+pub struct StageError {
+    pub stage: String,
+    /// The item being processed, if any.
+    pub id: Option<ItemId>,
+    /// Debug representation of the item, for those the stage gave up on: rejected,
+    /// late or unrouted, or that made it panic if it describes its items, see
+    /// `StageConfig::describe_items`. Items are not described otherwise, there is no telling
+    /// beforehand which of them will be reported.
+    pub item: Option<String>,
+    pub kind: StageErrorKind,
+}
+
+#[derive(Clone, Debug)]
+pub enum StageErrorKind {
+    Panicked(String),
+    /// A fallible stage returned an error for the item.
+    Rejected(String),
+    /// The item was dropped because the link downstream was full.
+    LinkFull,
+    /// The stage stopped sending to the link downstream, which was full.
+    Disconnected,
+    /// No rule of a router picked a branch for the item.
+    Unrouted,
+    /// The item came after the windows it belongs to were closed,
+    /// this far behind the watermark.
+    Late(Duration),
+}
+
+impl fmt::Display for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            StageErrorKind::Panicked(message) => {
+                write!(f, "stage {:?} panicked: {}", self.stage, message)?
+            }
+            StageErrorKind::Rejected(message) => {
+                write!(f, "stage {:?} rejected an item: {}", self.stage, message)?
+            }
+            StageErrorKind::LinkFull => {
+                write!(f, "stage {:?} dropped an item: link full", self.stage)?
+            }
+            StageErrorKind::Disconnected => write!(
+                f,
+                "stage {:?} disconnected from a consumer: link full",
+                self.stage
+            )?,
+            StageErrorKind::Unrouted => {
+                write!(f, "stage {:?} dropped an item: no route", self.stage)?
+            }
+            StageErrorKind::Late(by) => {
+                write!(f, "stage {:?} dropped an item: {:.2?} late", self.stage, by)?
+            }
+        }
+        match (&self.id, &self.item) {
+            (Some(id), Some(item)) => write!(f, " (item {}: {})", id, item)?,
+            (None, Some(item)) => write!(f, " (item: {})", item)?,
+            (Some(id), None) => write!(f, " (item {})", id)?,
+            (None, None) => {}
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for StageError {}
+
+/// How a single stage thread ended.
+#[derive(Debug)]
+pub struct StageReport {
+    pub stage: String,
+    pub result: Result<(), PipelineError>,
+}
+
+/// Best effort at getting a readable message out of a panic payload.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
@@ -0,0 +1,272 @@
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BinaryHeap, VecDeque};
+use std::future::{poll_fn, Future};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::{pin, Pin};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, JoinHandle, Thread};
+use std::time::Instant;
+
+use crate::wiring::lock;
+
+/// A task of an async pipeline, run until completion by an executor.
+pub type Task = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Runs the tasks of an async pipeline, see `AsyncPipeline::spawn`.
+/// Implement it to run pipelines on the executor of your choice.
+pub trait Executor {
+    fn spawn(&self, task: Task);
+}
+
+/// A fixed number of threads polling tasks as they are woken up.
+/// Dropping the pool waits for its threads to exit, dropping the tasks that are not done.
+/// A task that panics is dropped, without taking its thread down.
+pub struct ThreadPool {
+    queue: Arc<RunQueue>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+/// The tasks that were woken up, waiting for a thread to poll them.
+#[derive(Default)]
+struct RunQueue {
+    tasks: Mutex<VecDeque<Arc<TaskCell>>>,
+    available: Condvar,
+    stopped: AtomicBool,
+}
+
+impl RunQueue {
+    fn push(&self, task: Arc<TaskCell>) {
+        lock(&self.tasks).push_back(task);
+        self.available.notify_one();
+    }
+
+    /// Block until there is a task to poll, `None` once the pool is stopped.
+    fn pop(&self) -> Option<Arc<TaskCell>> {
+        let mut tasks = lock(&self.tasks);
+        loop {
+            if self.stopped.load(Ordering::SeqCst) {
+                return None;
+            }
+            if let Some(task) = tasks.pop_front() {
+                return Some(task);
+            }
+            tasks = self
+                .available
+                .wait(tasks)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+}
+
+struct TaskCell {
+    /// Gone once the task is done.
+    future: Mutex<Option<Task>>,
+    /// Whether the task is already waiting in the run queue, so it is queued only once.
+    scheduled: AtomicBool,
+    queue: Arc<RunQueue>,
+}
+
+impl Wake for TaskCell {
+    fn wake(self: Arc<Self>) {
+        if !self.scheduled.swap(true, Ordering::SeqCst) {
+            self.queue.clone().push(self);
+        }
+    }
+}
+
+impl ThreadPool {
+    /// Start `threads` threads, failing if the OS refuses to start any of them,
+    /// in which case the ones already started are stopped.
+    pub fn new(threads: usize) -> io::Result<Self> {
+        assert!(threads > 0, "a thread pool needs at least one thread");
+        let mut pool = ThreadPool {
+            queue: Arc::new(RunQueue::default()),
+            threads: Vec::with_capacity(threads),
+        };
+        for i in 0..threads {
+            let queue = pool.queue.clone();
+            let thread = thread::Builder::new()
+                .name(format!("executor-{}", i))
+                .spawn(move || {
+                    while let Some(task) = queue.pop() {
+                        task.scheduled.store(false, Ordering::SeqCst);
+                        let waker = Waker::from(task.clone());
+                        let mut future = lock(&task.future);
+                        let poll = future.as_mut().map(|future| {
+                            let mut cx = Context::from_waker(&waker);
+                            panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(&mut cx)))
+                        });
+                        // A task that panicked is as good as done.
+                        if !matches!(poll, Some(Ok(Poll::Pending))) {
+                            *future = None;
+                        }
+                    }
+                })?;
+            pool.threads.push(thread);
+        }
+        Ok(pool)
+    }
+}
+
+impl Executor for ThreadPool {
+    fn spawn(&self, task: Task) {
+        let task = Arc::new(TaskCell {
+            future: Mutex::new(Some(task)),
+            scheduled: AtomicBool::new(false),
+            queue: self.queue.clone(),
+        });
+        task.wake();
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.queue.stopped.store(true, Ordering::SeqCst);
+        self.queue.available.notify_all();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+        // Break the cycles between the queue and the tasks left in it.
+        lock(&self.queue.tasks).clear();
+    }
+}
+
+/// Wakes up a thread blocked in `block_on`.
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run `future` to completion on the current thread, e.g. to wait for
+/// an async pipeline from synchronous code.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(out) = future.as_mut().poll(&mut cx) {
+            return out;
+        }
+        thread::park();
+    }
+}
+
+/// Let the other tasks run before going on.
+pub async fn yield_now() {
+    let mut yielded = false;
+    poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
+
+/// Wait until `deadline`, without blocking the executor's thread.
+/// Fails if the thread waking up sleeping tasks can't be started.
+pub async fn sleep_until(deadline: Instant) -> io::Result<()> {
+    poll_fn(|cx| {
+        if Instant::now() >= deadline {
+            return Poll::Ready(Ok(()));
+        }
+        match timer() {
+            Ok(timer) => timer.schedule(deadline, cx.waker().clone()),
+            Err(err) => return Poll::Ready(Err(err)),
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// A thread waking up the tasks sleeping until a deadline, the earliest first.
+struct Timer {
+    pending: Mutex<BinaryHeap<Alarm>>,
+    changed: Condvar,
+}
+
+struct Alarm {
+    deadline: Instant,
+    waker: Waker,
+}
+
+// Reversed, so the binary heap pops the earliest deadline first.
+impl Ord for Alarm {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+impl PartialOrd for Alarm {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Alarm {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for Alarm {}
+
+impl Timer {
+    fn schedule(&self, deadline: Instant, waker: Waker) {
+        lock(&self.pending).push(Alarm { deadline, waker });
+        self.changed.notify_one();
+    }
+
+    fn run(&self) {
+        let mut pending = lock(&self.pending);
+        loop {
+            let now = Instant::now();
+            while pending.peek().is_some_and(|alarm| alarm.deadline <= now) {
+                if let Some(alarm) = pending.pop() {
+                    alarm.waker.wake();
+                }
+            }
+            pending = match pending.peek() {
+                Some(alarm) => {
+                    let timeout = alarm.deadline - now;
+                    self.changed
+                        .wait_timeout(pending, timeout)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .0
+                }
+                None => self
+                    .changed
+                    .wait(pending)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()),
+            };
+        }
+    }
+}
+
+/// The timer shared by every sleeping task, started on first use.
+/// Starting it is tried again next time if the OS refused to.
+fn timer() -> io::Result<Arc<Timer>> {
+    static TIMER: Mutex<Option<Arc<Timer>>> = Mutex::new(None);
+    let mut started = lock(&TIMER);
+    if let Some(timer) = &*started {
+        return Ok(timer.clone());
+    }
+    let timer = Arc::new(Timer {
+        pending: Mutex::new(BinaryHeap::new()),
+        changed: Condvar::new(),
+    });
+    let running = timer.clone();
+    thread::Builder::new()
+        .name("timer".to_owned())
+        .spawn(move || running.run())?;
+    *started = Some(timer.clone());
+    Ok(timer)
+}
@@ -0,0 +1,500 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::cancel::CancellationToken;
+use crate::dispatch::{Dispatcher, RoundRobin, WorkerLoad};
+use crate::error::{PipelineError, StageErrorKind};
+use crate::link::{Backpressure, Envelope, Inbound, LinkConfig, LinkMetrics, Outbound, SendError};
+use crate::log;
+use crate::stage::{Stage, StageConfig};
+use crate::wiring::{lock, spawn_ordered_worker, spawn_worker, Route, Wiring};
+
+/// How long the dispatcher of an ordered fan-out waits for the reorder buffer
+/// before checking for cancellation again.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How a fan-out stage is set up.
+pub struct FanOut<T> {
+    pub(crate) workers: Option<usize>,
+    pub(crate) dispatcher: Box<dyn Dispatcher<T>>,
+    /// The size of the reorder buffer, if the output has to keep the input's order.
+    pub(crate) ordered: Option<usize>,
+}
+
+impl<T> FanOut<T> {
+    pub fn new() -> Self {
+        FanOut {
+            workers: None,
+            dispatcher: Box::new(RoundRobin::default()),
+            ordered: None,
+        }
+    }
+
+    /// How many workers to start with. Defaults to the available parallelism.
+    pub fn workers(mut self, workers: usize) -> Self {
+        assert!(workers > 0, "a fan-out stage needs at least one worker");
+        self.workers = Some(workers);
+        self
+    }
+
+    /// How items are distributed over the workers. Defaults to `RoundRobin`.
+    pub fn dispatcher(mut self, dispatcher: impl Dispatcher<T>) -> Self {
+        self.dispatcher = Box::new(dispatcher);
+        self
+    }
+
+    /// Emit the workers' outputs in the order their inputs came in, rather than in whatever
+    /// order the workers finish. Items are numbered as they enter the fan-out and put back
+    /// in order after it, holding at most `reorder_buffer` of them: past that, no more items
+    /// are given to the workers until the oldest one is done.
+    ///
+    /// Items dropped along the way (rejected, or lost to a panic) don't hold the others back.
+    /// To not lose any without notice, the links to the workers always block when full.
+    pub fn ordered(mut self, reorder_buffer: usize) -> Self {
+        assert!(reorder_buffer > 0, "the reorder buffer can't be empty");
+        self.ordered = Some(reorder_buffer);
+        self
+    }
+}
+
+impl<T> Default for FanOut<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Adds and retires the workers of a running fan-out stage,
+/// see `PipelineHandle::pool`.
+#[derive(Clone)]
+pub struct WorkerPool {
+    name: String,
+    control: Arc<dyn PoolControl>,
+}
+
+impl WorkerPool {
+    /// The name of the fan-out stage.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// How many workers are currently receiving work.
+    pub fn len(&self) -> usize {
+        self.control.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Spawn one more worker, which starts receiving work right away.
+    pub fn add_worker(&self) -> Result<(), PipelineError> {
+        self.control.add()
+    }
+
+    /// Stop giving work to one of the workers, which exits once it is done with what it has
+    /// already been given. The last worker is never retired: returns `false` instead.
+    pub fn retire_worker(&self) -> bool {
+        self.control.retire()
+    }
+
+    /// Add or retire workers until there are `workers` of them.
+    pub fn resize(&self, workers: usize) -> Result<(), PipelineError> {
+        assert!(workers > 0, "a fan-out stage needs at least one worker");
+        while self.len() < workers {
+            self.add_worker()?;
+        }
+        while self.len() > workers && self.retire_worker() {}
+        Ok(())
+    }
+}
+
+/// The part of a pool that doesn't depend on the type of the items.
+trait PoolControl: Send + Sync {
+    fn len(&self) -> usize;
+    fn add(&self) -> Result<(), PipelineError>;
+    fn retire(&self) -> bool;
+}
+
+type Spawner<T> = Box<dyn FnMut() -> Result<Worker<T>, PipelineError> + Send>;
+
+struct Worker<T> {
+    id: usize,
+    /// The worker's own link, unless the workers share a queue.
+    link: Option<Arc<Outbound<T>>>,
+    /// The link the worker receives from, for its depth.
+    metrics: Arc<LinkMetrics>,
+    /// Only observed by workers sharing a queue, the others stop when their link is dropped.
+    retired: Arc<AtomicBool>,
+}
+
+struct Pool<T> {
+    name: String,
+    workers: Mutex<Vec<Worker<T>>>,
+    /// Gone once the dispatcher has stopped, as there is no more work to give.
+    spawner: Mutex<Option<Spawner<T>>>,
+}
+
+impl<T> Pool<T> {
+    fn remove(&self, link: &Arc<Outbound<T>>) {
+        lock(&self.workers).retain(|worker| {
+            !worker
+                .link
+                .as_ref()
+                .is_some_and(|other| Arc::ptr_eq(other, link))
+        });
+    }
+
+    fn close(&self) {
+        lock(&self.spawner).take();
+        lock(&self.workers).clear();
+    }
+}
+
+impl<T: Send> PoolControl for Pool<T> {
+    fn len(&self) -> usize {
+        lock(&self.workers).len()
+    }
+
+    fn add(&self) -> Result<(), PipelineError> {
+        let mut spawner = lock(&self.spawner);
+        let spawn = spawner.as_mut().ok_or_else(|| PipelineError::PoolStopped {
+            stage: self.name.clone(),
+        })?;
+        let worker = spawn()?;
+        lock(&self.workers).push(worker);
+        Ok(())
+    }
+
+    fn retire(&self) -> bool {
+        let mut workers = lock(&self.workers);
+        if workers.len() <= 1 {
+            return false;
+        }
+        // Workers sharing a queue stop taking items from it once retired, the others
+        // stop once they have drained their own link, dropped along with them here.
+        if let Some(worker) = workers.pop() {
+            worker.retired.store(true, Ordering::SeqCst);
+        }
+        true
+    }
+}
+
+/// How many workers a fan-out stage starts with when not told, see `FanOut::workers`.
+pub(crate) fn default_workers() -> usize {
+    thread::available_parallelism()
+        .map(NonZeroUsize::get)
+        .unwrap_or(1)
+}
+
+/// Spawn the workers and the dispatcher of a fan-out stage, returning the link
+/// carrying the merged outputs of the workers.
+pub(crate) fn spawn_fan_out<T, Out, U, S>(
+    wiring: &Wiring,
+    config: &StageConfig,
+    fan_out: FanOut<T>,
+    stage: S,
+    route: Route<Out, U>,
+    inbound: Inbound<Envelope<T>>,
+) -> Result<Inbound<Envelope<U>>, PipelineError>
+where
+    T: Send + Debug + 'static,
+    Out: 'static,
+    U: Send + 'static,
+    S: Stage<T, Out> + Clone,
+{
+    let FanOut {
+        workers,
+        dispatcher,
+        ordered,
+    } = fan_out;
+    let workers = workers.unwrap_or_else(default_workers);
+    let Some(reorder_buffer) = ordered else {
+        let (merge_tx, merge_rx) = wiring.link(config.name.clone(), config.link);
+        let spawn = move |wiring: &Wiring, config: &StageConfig, rx, tx| {
+            spawn_worker(wiring, config, stage.clone(), route, rx, tx)
+        };
+        let pool = PoolConfig {
+            workers,
+            dispatcher,
+            link: config.link,
+            payload: item::<T>,
+        };
+        spawn_pool(wiring, config, pool, inbound, merge_tx, Some, spawn)?;
+        return Ok(merge_rx);
+    };
+
+    let window = Arc::new(ReorderWindow {
+        next: Mutex::new(0),
+        advanced: Condvar::new(),
+        capacity: reorder_buffer as u64,
+        closed: AtomicBool::new(false),
+        token: wiring.token.clone(),
+    });
+    let spawn = move |wiring: &Wiring, config: &StageConfig, rx, tx| {
+        spawn_ordered_worker(wiring, config, stage.clone(), route, rx, tx)
+    };
+    // Numbered items can't be dropped on the way to the merge, or it would wait for them forever.
+    let link = LinkConfig {
+        backpressure: Backpressure::Block,
+        ..config.link
+    };
+    let (merge_tx, merge_rx) = wiring.link(config.name.clone(), link);
+    let pool = PoolConfig {
+        workers,
+        dispatcher,
+        link,
+        payload: numbered_item::<T>,
+    };
+    let mut seq = 0;
+    let number = {
+        let window = window.clone();
+        move |item| {
+            let numbered = window.wait_for(seq).then_some((seq, item));
+            seq += 1;
+            numbered
+        }
+    };
+    spawn_pool(wiring, config, pool, inbound, merge_tx, number, spawn)?;
+
+    let mut merge_config = config.clone();
+    merge_config.name = format!("{}-merge", config.name);
+    let (tx, rx) = wiring.link(merge_config.name.clone(), config.link);
+    wiring.spawn(&merge_config, move |ctx| {
+        let mut buffer = BTreeMap::new();
+        let mut next = 0;
+        'merge: while let Some((seq, out)) = merge_rx.recv() {
+            buffer.insert(seq, out);
+            while let Some(out) = buffer.remove(&next) {
+                next += 1;
+                if let Some(out) = out {
+                    log::enter_item(Some(out.id));
+                    if !ctx.forward(&tx, out) {
+                        break 'merge;
+                    }
+                }
+            }
+            window.advance_to(next);
+        }
+        // Downstream may be gone before upstream is done, don't leave the dispatcher waiting.
+        window.close();
+        Ok(())
+    })?;
+    Ok(rx)
+}
+
+/// Lets an ordered fan-out give items to its workers only as long as
+/// the reorder buffer would not overflow.
+struct ReorderWindow {
+    /// The next item to come out of the reorder buffer.
+    next: Mutex<u64>,
+    advanced: Condvar,
+    capacity: u64,
+    /// Set once the reorder buffer is gone, so no item will ever fit.
+    closed: AtomicBool,
+    token: CancellationToken,
+}
+
+impl ReorderWindow {
+    /// Block until item `seq` fits in the reorder buffer.
+    /// `false` if cancelled or closed meanwhile.
+    fn wait_for(&self, seq: u64) -> bool {
+        let mut next = lock(&self.next);
+        loop {
+            if self.token.is_cancelled() || self.closed.load(Ordering::SeqCst) {
+                return false;
+            }
+            if seq < *next + self.capacity {
+                return true;
+            }
+            next = self
+                .advanced
+                .wait_timeout(next, POLL_INTERVAL)
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+    }
+
+    fn advance_to(&self, next: u64) {
+        *lock(&self.next) = next;
+        self.advanced.notify_all();
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.advanced.notify_all();
+    }
+}
+
+/// How the workers of a fan-out stage are set up, `W` being what goes through
+/// the links from the dispatcher to the workers: the items themselves, or numbered items.
+struct PoolConfig<T, W> {
+    workers: usize,
+    dispatcher: Box<dyn Dispatcher<T>>,
+    link: LinkConfig,
+    /// The item the dispatcher looks at to pick a worker.
+    payload: fn(&W) -> &T,
+}
+
+fn item<T>(envelope: &Envelope<T>) -> &T {
+    &envelope.item
+}
+
+fn numbered_item<T>(numbered: &(u64, Envelope<T>)) -> &T {
+    &numbered.1.item
+}
+
+/// Spawn the workers of a fan-out stage, all sending into `merge_tx`, and the dispatcher
+/// giving them the items from `inbound`, after turning them into `W` with `prepare`,
+/// which returns `None` if the fan-out should stop.
+fn spawn_pool<T, I, W, M, P, F>(
+    wiring: &Wiring,
+    config: &StageConfig,
+    pool: PoolConfig<T, W>,
+    inbound: Inbound<I>,
+    merge_tx: Outbound<M>,
+    mut prepare: P,
+    mut spawn: F,
+) -> Result<(), PipelineError>
+where
+    T: Send + 'static,
+    I: Send + 'static,
+    W: Send + 'static,
+    M: Send + 'static,
+    P: FnMut(I) -> Option<W> + Send + 'static,
+    F: FnMut(&Wiring, &StageConfig, Inbound<W>, Outbound<M>) -> Result<(), PipelineError>
+        + Send
+        + 'static,
+{
+    let PoolConfig {
+        workers,
+        mut dispatcher,
+        link,
+        payload,
+    } = pool;
+    let mut dispatch_config = config.clone();
+    dispatch_config.name = format!("{}-dispatch", config.name);
+    // Where the dispatcher puts everything when the workers share a queue.
+    let shared_queue = dispatcher.shares_queue().then(|| {
+        let link_name = format!("{}->{}", dispatch_config.name, config.name);
+        wiring.link(link_name, link).0
+    });
+
+    let mut next_id = 0;
+    let spawner = {
+        let wiring = wiring.clone();
+        let config = config.clone();
+        let dispatch_name = dispatch_config.name.clone();
+        let shared_queue = shared_queue.clone();
+        move || {
+            let id = next_id;
+            next_id += 1;
+            let mut worker_config = config.clone();
+            worker_config.name = format!("{}-{}", config.name, id);
+            let retired = Arc::new(AtomicBool::new(false));
+            let (link, rx) = match &shared_queue {
+                Some(queue) => (None, queue.subscribe(retired.clone())),
+                None => {
+                    let link_name = format!("{}->{}", dispatch_name, worker_config.name);
+                    let (tx, rx) = wiring.link(link_name, link);
+                    (Some(Arc::new(tx)), rx)
+                }
+            };
+            let metrics = rx.metrics();
+            spawn(&wiring, &worker_config, rx, merge_tx.clone())?;
+            Ok(Worker {
+                id,
+                link,
+                metrics,
+                retired,
+            })
+        }
+    };
+    let pool = Arc::new(Pool {
+        name: config.name.clone(),
+        workers: Mutex::new(Vec::new()),
+        spawner: Mutex::new(Some(Box::new(spawner) as Spawner<W>)),
+    });
+    for _ in 0..workers {
+        pool.add()?;
+    }
+    lock(&wiring.pools).push(WorkerPool {
+        name: config.name.clone(),
+        control: pool.clone(),
+    });
+
+    wiring.spawn(&dispatch_config, move |ctx| {
+        let mut loads = Vec::new();
+        'items: while let Some(item) = inbound.recv() {
+            let Some(mut item) = prepare(item) else {
+                break;
+            };
+            if let Some(queue) = &shared_queue {
+                match queue.send(item) {
+                    Ok(()) => continue,
+                    Err(SendError::Full(_)) => {
+                        ctx.report(None, StageErrorKind::LinkFull);
+                        continue;
+                    }
+                    Err(SendError::Disconnected(_)) => {
+                        ctx.report(None, StageErrorKind::Disconnected);
+                        break;
+                    }
+                    Err(SendError::Closed(_)) => break,
+                }
+            }
+            // Hand the item to the worker picked by the dispatcher,
+            // forgetting about the workers that have stopped receiving.
+            loop {
+                let link = {
+                    let workers = lock(&pool.workers);
+                    if workers.is_empty() {
+                        break 'items;
+                    }
+                    loads.clear();
+                    loads.extend(workers.iter().map(|worker| WorkerLoad {
+                        id: worker.id,
+                        depth: worker.metrics.depth(),
+                    }));
+                    let selected = dispatcher
+                        .select(payload(&item), &loads)
+                        .min(workers.len() - 1);
+                    match &workers[selected].link {
+                        Some(link) => link.clone(),
+                        None => unreachable!("workers only share a queue with a shared queue"),
+                    }
+                };
+                match link.send(item) {
+                    Ok(()) => break,
+                    Err(SendError::Full(_)) => {
+                        ctx.report(None, StageErrorKind::LinkFull);
+                        break;
+                    }
+                    // A worker that can't keep up is given up on like a stopped one,
+                    // its item going to another worker. The last one is replaced first,
+                    // so the stage doesn't quietly stop halfway through the stream.
+                    Err(SendError::Disconnected(rejected)) => {
+                        ctx.report(None, StageErrorKind::Disconnected);
+                        if pool.len() == 1 {
+                            pool.add()?;
+                        }
+                        pool.remove(&link);
+                        item = rejected;
+                    }
+                    Err(SendError::Closed(rejected)) => {
+                        pool.remove(&link);
+                        item = rejected;
+                    }
+                }
+            }
+        }
+        // Only the workers hold the merge link from now on,
+        // so it closes once all of them are done.
+        pool.close();
+        Ok(())
+    })
+}
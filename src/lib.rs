@@ -0,0 +1,75 @@
+//! Building blocks for the pipeline pattern.
+//!
+//! refs:
+//! - <https://en.wikipedia.org/wiki/Pipeline_(software)>
+//! - <https://go.dev/blog/pipelines>: highlights an essential challenge - stages not exiting when they should, resulting in resource leak.
+//!
+//! A pipeline is a series of stages connected by channels.
+//! In each stage:
+//! - receive values from upstream via inbound channels
+//! - perform some function on that data, usually producing new values
+//! - send values downstream via outbound channels
+//!
+//! The use of channels for communication between stages means that stages can also be run in parallel.
+//!
+//! A pipeline starts from a [`Source`], goes through any number of stages, possibly spread
+//! over several workers with a [`FanOut`], and ends in a [`PipelineHandle`] to receive
+//! the results from, and to stop the stages with.
+//!
+//! ```
+//! use rconcurrency_stuff::{FanOut, Pipeline, Source, StageConfig};
+//!
+//! let pipeline = Pipeline::source("generate", Source::new(1..=4u32))
+//!     .fan_out("square", FanOut::new().workers(2).ordered(4), |num: u32| num * num)
+//!     .stage("merge", |squared: u32| squared + 1)
+//!     .spawn()?;
+//! let results: Vec<u32> = pipeline.iter().collect();
+//! assert_eq!(results, [2, 5, 10, 17]);
+//! for report in pipeline.join() {
+//!     report.result?;
+//! }
+//! # Ok::<(), rconcurrency_stuff::PipelineError>(())
+//! ```
+//!
+//! [`AsyncPipeline`] wires the same stages as tasks of an [`Executor`] instead of threads.
+
+mod async_link;
+pub mod async_pipeline;
+pub mod batch;
+pub mod cancel;
+pub mod dispatch;
+pub mod error;
+pub mod executor;
+pub mod fan_out;
+pub mod link;
+pub mod log;
+pub mod metrics;
+pub mod numeric;
+pub mod pipeline;
+pub mod router;
+pub mod sink;
+pub mod source;
+pub mod stage;
+pub mod testing;
+pub mod topology;
+pub mod watchdog;
+pub mod window;
+mod wiring;
+
+pub use async_pipeline::{AsyncPipeline, AsyncPipelineHandle, AsyncStage};
+pub use batch::Batch;
+pub use cancel::CancellationToken;
+pub use error::{PipelineError, StageError, StageErrorKind, StageReport};
+pub use executor::{block_on, Executor, ThreadPool};
+pub use fan_out::{FanOut, WorkerPool};
+pub use link::{Backpressure, LinkConfig, LinkStats};
+pub use log::{Level, Logger, StderrLogger};
+pub use metrics::{Metrics, MetricsServer, MetricsSnapshot};
+pub use pipeline::{Pipeline, PipelineHandle};
+pub use router::{Router, Rules};
+pub use sink::Sink;
+pub use source::Source;
+pub use stage::{PanicPolicy, Stage, StageConfig};
+pub use topology::Topology;
+pub use watchdog::Watchdog;
+pub use window::{Aggregate, Window, Windowed};
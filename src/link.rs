@@ -0,0 +1,472 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::cancel::CancellationToken;
+use crate::log::ItemId;
+use crate::watchdog::{self, Activity};
+
+/// How long a stage blocks on a link before checking for cancellation again.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What a stage does when the link it sends into is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Wait for downstream to make room.
+    #[default]
+    Block,
+    /// Drop the item being sent.
+    DropNewest,
+    /// Drop the oldest item waiting in the link to make room for the new one.
+    DropOldest,
+    /// Report the item being sent on the error channel and drop it.
+    Error,
+    /// Stop sending into the link altogether, as if downstream was gone, reporting it on the
+    /// error channel: a consumer that can't keep up is cut off, rather than holding up the stage
+    /// or missing items here and there. It still gets what was already in the link.
+    Disconnect,
+}
+
+/// How a link between two stages is set up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkConfig {
+    /// `None` for an unbounded link.
+    pub capacity: Option<usize>,
+    pub backpressure: Backpressure,
+}
+
+/// A snapshot of what went through a link.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LinkStats {
+    pub name: String,
+    pub capacity: Option<usize>,
+    /// Items currently waiting in the link.
+    pub depth: usize,
+    /// Highest depth seen so far.
+    pub high_water: usize,
+    pub sent: u64,
+    pub dropped: u64,
+    /// How many sends found the link full.
+    pub saturated: u64,
+}
+
+impl LinkStats {
+    pub fn is_saturated(&self) -> bool {
+        self.capacity.is_some_and(|capacity| self.depth >= capacity)
+    }
+}
+
+/// The counters behind `LinkStats`, kept outside of the queue's lock
+/// so they can be read without knowing the type of the items.
+#[derive(Debug)]
+pub(crate) struct LinkMetrics {
+    name: String,
+    capacity: Option<usize>,
+    depth: AtomicUsize,
+    high_water: AtomicUsize,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    saturated: AtomicU64,
+}
+
+impl LinkMetrics {
+    pub(crate) fn new(name: String, capacity: Option<usize>) -> Self {
+        LinkMetrics {
+            name,
+            capacity,
+            depth: AtomicUsize::new(0),
+            high_water: AtomicUsize::new(0),
+            sent: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            saturated: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> LinkStats {
+        LinkStats {
+            name: self.name.clone(),
+            capacity: self.capacity,
+            depth: self.depth.load(Ordering::Relaxed),
+            high_water: self.high_water.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            saturated: self.saturated.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_depth(&self, depth: usize) {
+        self.depth.store(depth, Ordering::Relaxed);
+        self.high_water.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    /// An item was sent, leaving `depth` items in the link.
+    pub(crate) fn sent(&self, depth: usize) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        self.set_depth(depth);
+    }
+
+    pub(crate) fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn saturated(&self) {
+        self.saturated.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receivers: usize,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    config: LinkConfig,
+    metrics: Arc<LinkMetrics>,
+    token: CancellationToken,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        // Stages don't panic while holding the lock, but the pipeline should still
+        // be able to shut down if one somehow did.
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// An item on its way between two stages, tagged with its id so it can be traced.
+#[derive(Debug)]
+pub(crate) struct Envelope<T> {
+    pub(crate) id: ItemId,
+    pub(crate) item: T,
+}
+
+/// Create the link between two stages, both ends observing `token`.
+pub(crate) fn link<T>(
+    name: String,
+    config: LinkConfig,
+    token: &CancellationToken,
+) -> (Outbound<T>, Inbound<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::new(),
+            senders: 1,
+            receivers: 1,
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        config,
+        metrics: Arc::new(LinkMetrics::new(name, config.capacity)),
+        token: token.clone(),
+    });
+    (
+        Outbound {
+            shared: shared.clone(),
+        },
+        Inbound {
+            shared,
+            retired: None,
+        },
+    )
+}
+
+/// The receiving end of a link: where a stage gets its values from upstream.
+/// Several stages can receive from the same link, each item going to only one of them.
+pub(crate) struct Inbound<T> {
+    shared: Arc<Shared<T>>,
+    /// Lets this receiver stop while the others keep going.
+    retired: Option<Arc<AtomicBool>>,
+}
+
+impl<T> Inbound<T> {
+    /// Block until a value arrives. `None` means the stage should stop:
+    /// either upstream is gone, the pipeline has been cancelled or this receiver was retired.
+    pub(crate) fn recv(&self) -> Option<T> {
+        self.recv_deadline(None).ok()
+    }
+
+    /// Same as `recv`, giving up at `deadline` if there is one.
+    /// `Disconnected` means the stage should stop.
+    pub(crate) fn recv_deadline(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        let shared = &self.shared;
+        let mut state = shared.lock();
+        let mut waited = false;
+        while !shared.token.is_cancelled() && !self.is_retired() {
+            if let Some(item) = state.queue.pop_front() {
+                shared.metrics.set_depth(state.queue.len());
+                shared.not_full.notify_one();
+                if waited {
+                    watchdog::set_activity(Activity::Running);
+                }
+                return Ok(item);
+            }
+            if state.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let mut timeout = POLL_INTERVAL;
+            if let Some(deadline) = deadline {
+                let now = Instant::now();
+                if now >= deadline {
+                    if waited {
+                        watchdog::set_activity(Activity::Running);
+                    }
+                    return Err(RecvTimeoutError::Timeout);
+                }
+                timeout = timeout.min(deadline - now);
+            }
+            if !waited {
+                waited = true;
+                watchdog::set_activity(Activity::Receiving {
+                    link: shared.metrics.name().to_owned(),
+                });
+            }
+            state = shared
+                .not_empty
+                .wait_timeout(state, timeout)
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+        Err(RecvTimeoutError::Disconnected)
+    }
+
+    pub(crate) fn metrics(&self) -> Arc<LinkMetrics> {
+        self.shared.metrics.clone()
+    }
+
+    fn is_retired(&self) -> bool {
+        self.retired
+            .as_ref()
+            .is_some_and(|retired| retired.load(Ordering::SeqCst))
+    }
+}
+
+impl<T> Drop for Inbound<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.receivers -= 1;
+        if state.receivers == 0 {
+            // Whatever is left will never be received.
+            state.queue.clear();
+            self.shared.metrics.set_depth(0);
+            self.shared.not_full.notify_all();
+        }
+    }
+}
+
+/// Why an item could not be sent.
+pub(crate) enum SendError<T> {
+    /// Downstream is gone or the pipeline has been cancelled: the stage should stop.
+    Closed(T),
+    /// The link is full and its backpressure policy is `Backpressure::Error`.
+    Full(T),
+    /// The link is full and its backpressure policy is `Backpressure::Disconnect`:
+    /// the stage should stop sending to it.
+    Disconnected(T),
+}
+
+/// The sending end of a link: where a stage puts its values for downstream.
+pub(crate) struct Outbound<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Outbound<T> {
+    /// Hand a value to downstream, applying the link's backpressure policy if it is full.
+    pub(crate) fn send(&self, item: T) -> Result<(), SendError<T>> {
+        let shared = &self.shared;
+        let metrics = &shared.metrics;
+        let mut state = shared.lock();
+        let mut saturated = false;
+        loop {
+            if shared.token.is_cancelled() || state.receivers == 0 {
+                if saturated {
+                    watchdog::set_activity(Activity::Running);
+                }
+                return Err(SendError::Closed(item));
+            }
+            let full = shared
+                .config
+                .capacity
+                .is_some_and(|capacity| state.queue.len() >= capacity);
+            if !full {
+                if saturated {
+                    watchdog::set_activity(Activity::Running);
+                }
+                break;
+            }
+            if !saturated {
+                saturated = true;
+                metrics.saturated();
+            }
+            match shared.config.backpressure {
+                Backpressure::Block => {
+                    watchdog::set_activity(Activity::Sending {
+                        link: metrics.name().to_owned(),
+                    });
+                    state = shared
+                        .not_full
+                        .wait_timeout(state, POLL_INTERVAL)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .0;
+                }
+                Backpressure::DropNewest => {
+                    metrics.dropped();
+                    return Ok(());
+                }
+                Backpressure::DropOldest => {
+                    state.queue.pop_front();
+                    metrics.dropped();
+                    break;
+                }
+                Backpressure::Error => {
+                    metrics.dropped();
+                    return Err(SendError::Full(item));
+                }
+                Backpressure::Disconnect => {
+                    metrics.dropped();
+                    return Err(SendError::Disconnected(item));
+                }
+            }
+        }
+        state.queue.push_back(item);
+        metrics.sent(state.queue.len());
+        shared.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// One more receiver of this link, which stops receiving once `retired` is set.
+    pub(crate) fn subscribe(&self, retired: Arc<AtomicBool>) -> Inbound<T> {
+        self.shared.lock().receivers += 1;
+        Inbound {
+            shared: self.shared.clone(),
+            retired: Some(retired),
+        }
+    }
+}
+
+impl<T> Clone for Outbound<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Outbound {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Outbound<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.shared.not_empty.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+    use std::thread;
+
+    use super::*;
+
+    /// A link holding two items, already full with `1` and `2`.
+    fn full(backpressure: Backpressure) -> (Outbound<u32>, Inbound<u32>) {
+        let config = LinkConfig {
+            capacity: Some(2),
+            backpressure,
+        };
+        let (tx, rx) = link("full".to_owned(), config, &CancellationToken::new());
+        assert!(tx.send(1).is_ok());
+        assert!(tx.send(2).is_ok());
+        assert!(rx.metrics().stats().is_saturated());
+        (tx, rx)
+    }
+
+    /// Everything that was left in the link once upstream is gone.
+    fn drain(tx: Outbound<u32>, rx: Inbound<u32>) -> Vec<u32> {
+        drop(tx);
+        iter::from_fn(|| rx.recv()).collect()
+    }
+
+    fn stats(rx: &Inbound<u32>) -> (u64, u64, u64, usize) {
+        let stats = rx.metrics().stats();
+        (stats.sent, stats.dropped, stats.saturated, stats.high_water)
+    }
+
+    #[test]
+    fn blocking_links_wait_for_room() {
+        let (tx, rx) = full(Backpressure::Block);
+        let sender = thread::spawn(move || {
+            assert!(tx.send(3).is_ok());
+            tx
+        });
+        thread::sleep(Duration::from_millis(20));
+        assert!(!sender.is_finished(), "the link had no room");
+        assert_eq!(rx.recv(), Some(1));
+        let tx = sender.join().unwrap();
+        assert_eq!(stats(&rx), (3, 0, 1, 2));
+        assert_eq!(drain(tx, rx), [2, 3]);
+    }
+
+    #[test]
+    fn dropping_links_drop_the_newest_item() {
+        let (tx, rx) = full(Backpressure::DropNewest);
+        assert!(tx.send(3).is_ok());
+        assert_eq!(stats(&rx), (2, 1, 1, 2));
+        assert_eq!(drain(tx, rx), [1, 2]);
+    }
+
+    #[test]
+    fn dropping_links_drop_the_oldest_item() {
+        let (tx, rx) = full(Backpressure::DropOldest);
+        assert!(tx.send(3).is_ok());
+        assert_eq!(stats(&rx), (3, 1, 1, 2));
+        assert_eq!(drain(tx, rx), [2, 3]);
+    }
+
+    #[test]
+    fn erroring_links_hand_the_item_back() {
+        let (tx, rx) = full(Backpressure::Error);
+        assert!(matches!(tx.send(3), Err(SendError::Full(3))));
+        assert_eq!(stats(&rx), (2, 1, 1, 2));
+        assert_eq!(drain(tx, rx), [1, 2]);
+    }
+
+    #[test]
+    fn disconnecting_links_keep_what_they_hold() {
+        let (tx, rx) = full(Backpressure::Disconnect);
+        assert!(matches!(tx.send(3), Err(SendError::Disconnected(3))));
+        assert_eq!(stats(&rx), (2, 1, 1, 2));
+        assert_eq!(drain(tx, rx), [1, 2]);
+    }
+
+    #[test]
+    fn links_are_saturated_only_when_full() {
+        let (_tx, rx) = full(Backpressure::DropNewest);
+        assert_eq!(rx.recv(), Some(1));
+        let stats = rx.metrics().stats();
+        assert_eq!((stats.depth, stats.high_water), (1, 2));
+        assert!(!stats.is_saturated());
+        let (_, unbounded) = link::<u32>(
+            "unbounded".to_owned(),
+            LinkConfig::default(),
+            &CancellationToken::new(),
+        );
+        assert!(!unbounded.metrics().stats().is_saturated());
+    }
+}
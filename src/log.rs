@@ -0,0 +1,176 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::watchdog;
+
+/// How important a log event is, from the most to the least.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        };
+        f.pad(name)
+    }
+}
+
+/// Identifies an item from the source it came out of to the end of the pipeline,
+/// whatever it is turned into along the way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ItemId(pub u64);
+
+impl fmt::Display for ItemId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// A log event, within the span of the stage it happened in.
+#[derive(Debug)]
+pub struct Record<'a> {
+    pub level: Level,
+    pub stage: &'a str,
+    /// The item being processed, if any.
+    pub item: Option<ItemId>,
+    pub message: fmt::Arguments<'a>,
+}
+
+/// Where the log events of a pipeline go, see `Pipeline::logger`.
+pub trait Logger: Send + Sync + 'static {
+    /// Whether events of this level are wanted at all, checked before formatting them.
+    fn enabled(&self, level: Level) -> bool;
+
+    fn log(&self, record: &Record<'_>);
+}
+
+/// Log to stderr, one line per event, e.g. `[INFO  square-0 #3] squaring 3`.
+#[derive(Clone, Copy, Debug)]
+pub struct StderrLogger {
+    max_level: Level,
+}
+
+impl StderrLogger {
+    /// Log the events of `max_level` and the more important ones.
+    pub fn new(max_level: Level) -> Self {
+        StderrLogger { max_level }
+    }
+}
+
+impl Logger for StderrLogger {
+    fn enabled(&self, level: Level) -> bool {
+        level <= self.max_level
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        // Lock so lines from different stages don't interleave.
+        let mut stderr = std::io::stderr().lock();
+        let _ = match record.item {
+            Some(item) => writeln!(
+                stderr,
+                "[{:<5} {} {}] {}",
+                record.level, record.stage, item, record.message
+            ),
+            None => writeln!(
+                stderr,
+                "[{:<5} {}] {}",
+                record.level, record.stage, record.message
+            ),
+        };
+    }
+}
+
+/// Drop every event. The default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Silent;
+
+impl Logger for Silent {
+    fn enabled(&self, _: Level) -> bool {
+        false
+    }
+
+    fn log(&self, _: &Record<'_>) {}
+}
+
+/// What the events logged on a stage thread are attached to.
+struct Span {
+    stage: String,
+    item: Option<ItemId>,
+    logger: Arc<dyn Logger>,
+}
+
+thread_local! {
+    static SPAN: RefCell<Option<Span>> = const { RefCell::new(None) };
+}
+
+/// Attach the events logged on this thread to `stage`, sending them to `logger`.
+pub(crate) fn enter_stage(stage: &str, logger: Arc<dyn Logger>) {
+    SPAN.with(|span| {
+        *span.borrow_mut() = Some(Span {
+            stage: stage.to_owned(),
+            item: None,
+            logger,
+        });
+    });
+}
+
+/// Attach the events logged on this thread to `item`, until the next call.
+/// The watchdog is told as well, to report what a stuck stage was working on.
+pub(crate) fn enter_item(item: Option<ItemId>) {
+    watchdog::set_item(item);
+    SPAN.with(|span| {
+        if let Some(span) = span.borrow_mut().as_mut() {
+            span.item = item;
+        }
+    });
+}
+
+/// The item the stage running on this thread is processing, if any.
+pub(crate) fn current_item() -> Option<ItemId> {
+    SPAN.with(|span| span.borrow().as_ref().and_then(|span| span.item))
+}
+
+/// Detach this thread from the stage it was running, e.g. when an async stage yields
+/// to the executor, returning the item the stage was processing.
+pub(crate) fn leave_stage() -> Option<ItemId> {
+    SPAN.with(|span| span.borrow_mut().take().and_then(|span| span.item))
+}
+
+/// Log an event within the current span: the stage running on this thread
+/// and the item it is processing. Events logged outside of a stage are dropped.
+/// Usually called through the `event!` macro.
+pub fn event(level: Level, message: fmt::Arguments<'_>) {
+    SPAN.with(|span| {
+        if let Some(span) = span.borrow().as_ref() {
+            if span.logger.enabled(level) {
+                span.logger.log(&Record {
+                    level,
+                    stage: &span.stage,
+                    item: span.item,
+                    message,
+                });
+            }
+        }
+    });
+}
+
+/// Log an event from within a stage, e.g. `event!(Level::Info, "squaring {}", num)`.
+#[macro_export]
+macro_rules! event {
+    ($level:expr, $($arg:tt)+) => {
+        $crate::log::event($level, format_args!($($arg)+))
+    };
+}
@@ -0,0 +1,499 @@
+use std::fmt::{self, Write as _};
+use std::io::{self, BufRead, BufReader, Write as _};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::link::{LinkMetrics, LinkStats};
+use crate::wiring::lock;
+
+/// How long the metrics server waits for a connection before checking if it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Upper bounds of the buckets of the processing time histograms, the last bucket being unbounded.
+const BUCKETS: [Duration; 7] = [
+    Duration::from_micros(10),
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+];
+
+/// The counters behind `StageStats`, updated by the stage thread as it goes.
+#[derive(Debug)]
+pub(crate) struct StageMetrics {
+    name: String,
+    processed: AtomicU64,
+    failed: AtomicU64,
+    in_flight: AtomicUsize,
+    /// One more than `BUCKETS`, for the processing times above the last bound.
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    total_nanos: AtomicU64,
+}
+
+impl StageMetrics {
+    pub(crate) fn new(name: String) -> Self {
+        StageMetrics {
+            name,
+            processed: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+            buckets: Default::default(),
+            total_nanos: AtomicU64::new(0),
+        }
+    }
+
+    /// Time `f` processing an item. `outcome` tells whether the item made it through
+    /// or was dropped, `None` meaning there was no item after all, e.g. at the end of a source.
+    pub(crate) fn track<R>(
+        &self,
+        f: impl FnOnce() -> R,
+        outcome: impl Fn(&R) -> Option<bool>,
+    ) -> R {
+        let started = self.start();
+        let out = f();
+        self.finish(started, outcome(&out));
+        out
+    }
+
+    /// Same as `track`, for when processing an item can't be wrapped in a closure,
+    /// e.g. when it is awaited.
+    pub(crate) fn start(&self) -> Instant {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        Instant::now()
+    }
+
+    pub(crate) fn finish(&self, started: Instant, outcome: Option<bool>) {
+        let elapsed = started.elapsed();
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+        let Some(succeeded) = outcome else {
+            return;
+        };
+        let bucket = BUCKETS.partition_point(|&bound| bound < elapsed);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        let counter = if succeeded {
+            &self.processed
+        } else {
+            &self.failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> StageStats {
+        let mut cumulative = 0;
+        let mut buckets = Vec::with_capacity(BUCKETS.len());
+        for (bound, count) in BUCKETS.iter().zip(&self.buckets) {
+            cumulative += count.load(Ordering::Relaxed);
+            buckets.push((*bound, cumulative));
+        }
+        let count = cumulative + self.buckets[BUCKETS.len()].load(Ordering::Relaxed);
+        StageStats {
+            name: self.name.clone(),
+            processed: self.processed.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            latency: Histogram {
+                buckets,
+                count,
+                total: Duration::from_nanos(self.total_nanos.load(Ordering::Relaxed)),
+            },
+        }
+    }
+}
+
+/// A snapshot of what a stage thread has done so far.
+/// Fan-out workers each have their own, e.g. `square-0`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StageStats {
+    pub name: String,
+    /// Items the stage was done with and passed on.
+    pub processed: u64,
+    /// Items the stage rejected or panicked on.
+    pub failed: u64,
+    /// Items the stage is processing right now.
+    pub in_flight: usize,
+    /// How long the stage took to process each item.
+    pub latency: Histogram,
+}
+
+/// How long items took to process, counted in buckets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Histogram {
+    /// Upper bound of each bucket, with the number of items that took at most that long.
+    /// The items that took even longer are only counted in `count`.
+    pub buckets: Vec<(Duration, u64)>,
+    pub count: u64,
+    pub total: Duration,
+}
+
+impl Histogram {
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let nanos = self.total.as_nanos() / u128::from(self.count);
+        Some(Duration::from_nanos(
+            u64::try_from(nanos).unwrap_or(u64::MAX),
+        ))
+    }
+
+    /// The upper bound of the bucket where the `q` quantile falls, e.g. `0.99` for the p99.
+    /// `None` if there's nothing to go on, or if it is past the last bucket.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        assert!((0.0..=1.0).contains(&q), "a quantile is between 0 and 1");
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (q * self.count as f64).ceil().max(1.0) as u64;
+        self.buckets
+            .iter()
+            .find(|&&(_, count)| count >= rank)
+            .map(|&(bound, _)| bound)
+    }
+}
+
+/// The live metrics of a pipeline, which can be sent to another thread,
+/// e.g. to serve them while the pipeline runs, see `PipelineHandle::metrics`.
+#[derive(Clone)]
+pub struct Metrics {
+    pub(crate) stages: Arc<Mutex<Vec<Arc<StageMetrics>>>>,
+    pub(crate) links: Arc<Mutex<Vec<Arc<LinkMetrics>>>>,
+}
+
+impl Metrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            stages: lock(&self.stages)
+                .iter()
+                .map(|stage| stage.stats())
+                .collect(),
+            links: lock(&self.links).iter().map(|link| link.stats()).collect(),
+        }
+    }
+}
+
+/// What every stage and link of a pipeline has done so far,
+/// in the order they were created.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub stages: Vec<StageStats>,
+    pub links: Vec<LinkStats>,
+}
+
+impl MetricsSnapshot {
+    /// Items that came out of the source but not out of the pipeline yet:
+    /// being processed or waiting in a link.
+    pub fn in_flight(&self) -> usize {
+        let processing: usize = self.stages.iter().map(|stage| stage.in_flight).sum();
+        let waiting: usize = self.links.iter().map(|link| link.depth).sum();
+        processing + waiting
+    }
+
+    /// Render the snapshot in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        // Writing to a `String` can't fail.
+        let _ = self.write_prometheus(&mut out);
+        out
+    }
+
+    fn write_prometheus(&self, out: &mut String) -> fmt::Result {
+        let stages = &self.stages;
+        family(
+            out,
+            ("pipeline_stage_processed_total", "counter"),
+            "Items processed by each stage.",
+            "stage",
+            stages.iter().map(|stage| (&stage.name, stage.processed)),
+        )?;
+        family(
+            out,
+            ("pipeline_stage_failed_total", "counter"),
+            "Items each stage rejected or panicked on.",
+            "stage",
+            stages.iter().map(|stage| (&stage.name, stage.failed)),
+        )?;
+        family(
+            out,
+            ("pipeline_stage_in_flight", "gauge"),
+            "Items being processed by each stage.",
+            "stage",
+            stages.iter().map(|stage| (&stage.name, stage.in_flight)),
+        )?;
+        let metric = "pipeline_stage_duration_seconds";
+        header(
+            out,
+            metric,
+            "histogram",
+            "Time each stage took to process an item.",
+        )?;
+        for stage in stages {
+            let name = Label(&stage.name);
+            let latency = &stage.latency;
+            for (bound, count) in &latency.buckets {
+                let le = bound.as_secs_f64();
+                writeln!(
+                    out,
+                    "{}_bucket{{stage=\"{}\",le=\"{}\"}} {}",
+                    metric, name, le, count
+                )?;
+            }
+            let count = latency.count;
+            writeln!(
+                out,
+                "{}_bucket{{stage=\"{}\",le=\"+Inf\"}} {}",
+                metric, name, count
+            )?;
+            let total = latency.total.as_secs_f64();
+            writeln!(out, "{}_sum{{stage=\"{}\"}} {}", metric, name, total)?;
+            writeln!(out, "{}_count{{stage=\"{}\"}} {}", metric, name, count)?;
+        }
+
+        let links = &self.links;
+        family(
+            out,
+            ("pipeline_link_depth", "gauge"),
+            "Items waiting in each link.",
+            "link",
+            links.iter().map(|link| (&link.name, link.depth)),
+        )?;
+        family(
+            out,
+            ("pipeline_link_high_water", "gauge"),
+            "Highest depth of each link so far.",
+            "link",
+            links.iter().map(|link| (&link.name, link.high_water)),
+        )?;
+        family(
+            out,
+            ("pipeline_link_sent_total", "counter"),
+            "Items sent into each link.",
+            "link",
+            links.iter().map(|link| (&link.name, link.sent)),
+        )?;
+        family(
+            out,
+            ("pipeline_link_dropped_total", "counter"),
+            "Items dropped by each link when full.",
+            "link",
+            links.iter().map(|link| (&link.name, link.dropped)),
+        )?;
+        family(
+            out,
+            ("pipeline_link_saturated_total", "counter"),
+            "Sends that found each link full.",
+            "link",
+            links.iter().map(|link| (&link.name, link.saturated)),
+        )
+    }
+}
+
+fn header(out: &mut String, metric: &str, kind: &str, help: &str) -> fmt::Result {
+    writeln!(out, "# HELP {} {}", metric, help)?;
+    writeln!(out, "# TYPE {} {}", metric, kind)
+}
+
+/// Write a metric with one value per stage or per link, `label` holding its name.
+fn family<'a, V: fmt::Display>(
+    out: &mut String,
+    (metric, kind): (&str, &str),
+    help: &str,
+    label: &str,
+    values: impl Iterator<Item = (&'a String, V)>,
+) -> fmt::Result {
+    header(out, metric, kind, help)?;
+    for (name, value) in values {
+        writeln!(out, "{}{{{}=\"{}\"}} {}", metric, label, Label(name), value)?;
+    }
+    Ok(())
+}
+
+/// A label value, escaped as the Prometheus format wants.
+struct Label<'a>(&'a str);
+
+impl fmt::Display for Label<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '\\' => f.write_str("\\\\")?,
+                '"' => f.write_str("\\\"")?,
+                '\n' => f.write_str("\\n")?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serves the metrics of a pipeline over HTTP, at `/metrics`, for Prometheus to scrape.
+/// Stops when dropped.
+pub struct MetricsServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Start serving `metrics` on `addr`, e.g. `127.0.0.1:9898`, from a thread of its own.
+    /// Port `0` picks any free port, see `local_addr`.
+    pub fn start(addr: impl ToSocketAddrs, metrics: Metrics) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        // So the server notices when it should stop.
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name("metrics".to_owned())
+                .spawn(move || {
+                    while !stop.load(Ordering::SeqCst) {
+                        match listener.accept() {
+                            // A scraper going away mid-request is its own problem.
+                            Ok((stream, _)) => {
+                                let _ = respond(stream, &metrics);
+                            }
+                            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                                thread::sleep(POLL_INTERVAL);
+                            }
+                            Err(_) => thread::sleep(POLL_INTERVAL),
+                        }
+                    }
+                })?
+        };
+        Ok(MetricsServer {
+            addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Answer a single HTTP request.
+fn respond(stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Skip the headers, the request line is all that matters.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut path = request.split_whitespace().skip(1);
+    let (status, body) = match (request.split_whitespace().next(), path.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.snapshot().to_prometheus()),
+        _ => ("404 Not Found", "not found\n".to_owned()),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> MetricsSnapshot {
+        MetricsSnapshot {
+            stages: vec![StageStats {
+                name: "square-0".to_owned(),
+                processed: 3,
+                failed: 1,
+                in_flight: 0,
+                latency: Histogram {
+                    buckets: BUCKETS.iter().map(|&bound| (bound, 0)).collect(),
+                    count: 4,
+                    total: Duration::from_millis(2),
+                },
+            }],
+            links: vec![LinkStats {
+                name: "a \"quoted\" link".to_owned(),
+                capacity: Some(8),
+                depth: 2,
+                high_water: 5,
+                sent: 7,
+                dropped: 0,
+                saturated: 1,
+            }],
+        }
+    }
+
+    #[test]
+    fn snapshots_render_in_the_text_exposition_format() {
+        let mut snapshot = snapshot();
+        snapshot.stages[0].latency.buckets[2].1 = 3;
+        let text = snapshot.to_prometheus();
+        let lines: Vec<_> = text.lines().collect();
+        for expected in [
+            "# HELP pipeline_stage_processed_total Items processed by each stage.",
+            "# TYPE pipeline_stage_processed_total counter",
+            "pipeline_stage_processed_total{stage=\"square-0\"} 3",
+            "pipeline_stage_failed_total{stage=\"square-0\"} 1",
+            "# TYPE pipeline_stage_in_flight gauge",
+            "# TYPE pipeline_stage_duration_seconds histogram",
+            "pipeline_stage_duration_seconds_bucket{stage=\"square-0\",le=\"0.001\"} 3",
+            "pipeline_stage_duration_seconds_bucket{stage=\"square-0\",le=\"+Inf\"} 4",
+            "pipeline_stage_duration_seconds_sum{stage=\"square-0\"} 0.002",
+            "pipeline_stage_duration_seconds_count{stage=\"square-0\"} 4",
+            "# TYPE pipeline_link_depth gauge",
+            "pipeline_link_depth{link=\"a \\\"quoted\\\" link\"} 2",
+            "pipeline_link_high_water{link=\"a \\\"quoted\\\" link\"} 5",
+            "pipeline_link_sent_total{link=\"a \\\"quoted\\\" link\"} 7",
+            "pipeline_link_saturated_total{link=\"a \\\"quoted\\\" link\"} 1",
+        ] {
+            assert!(
+                lines.contains(&expected),
+                "missing {:?} in:\n{}",
+                expected,
+                text
+            );
+        }
+        // Every sample comes after the type of its metric.
+        let mut metric = "";
+        for line in &lines {
+            match line.strip_prefix("# TYPE ") {
+                Some(declared) => metric = declared.split(' ').next().unwrap(),
+                None if line.starts_with('#') => {}
+                None => assert!(line.starts_with(metric), "{:?} is not a {}", line, metric),
+            }
+        }
+    }
+
+    #[test]
+    fn means_hold_for_any_count() {
+        let mut latency = snapshot().stages.remove(0).latency;
+        assert_eq!(latency.mean(), Some(Duration::from_micros(500)));
+        latency.count = u64::from(u32::MAX) * 4;
+        latency.total = Duration::from_secs(u64::from(u32::MAX) * 8);
+        assert_eq!(latency.mean(), Some(Duration::from_secs(2)));
+        latency.count = 0;
+        assert_eq!(latency.mean(), None);
+    }
+}
@@ -0,0 +1,378 @@
+use std::any::type_name;
+use std::cmp::Ordering;
+use std::fmt::{self, Debug, Display, Write};
+use std::marker::PhantomData;
+
+use crate::stage::Stage;
+
+/// The unsigned integers numeric stages work on, from `u8` to `u128` and `BigUint`.
+pub trait Integer: Clone + Debug + Display + Send + 'static {
+    fn checked_add(&self, rhs: &Self) -> Option<Self>;
+    fn checked_mul(&self, rhs: &Self) -> Option<Self>;
+    fn saturating_mul(&self, rhs: &Self) -> Self;
+    fn wrapping_mul(&self, rhs: &Self) -> Self;
+}
+
+macro_rules! impl_integer {
+    ($($int:ty),*) => {$(
+        impl Integer for $int {
+            fn checked_add(&self, rhs: &Self) -> Option<Self> {
+                <$int>::checked_add(*self, *rhs)
+            }
+
+            fn checked_mul(&self, rhs: &Self) -> Option<Self> {
+                <$int>::checked_mul(*self, *rhs)
+            }
+
+            fn saturating_mul(&self, rhs: &Self) -> Self {
+                <$int>::saturating_mul(*self, *rhs)
+            }
+
+            fn wrapping_mul(&self, rhs: &Self) -> Self {
+                <$int>::wrapping_mul(*self, *rhs)
+            }
+        }
+    )*};
+}
+
+impl_integer!(u8, u16, u32, u64, u128, usize);
+
+/// Integers that have a type twice as wide, big enough for their square.
+pub trait Widen: Integer {
+    type Wide: Integer;
+
+    fn widen(self) -> Self::Wide;
+}
+
+macro_rules! impl_widen {
+    ($($int:ty => $wide:ty),*) => {$(
+        impl Widen for $int {
+            type Wide = $wide;
+
+            fn widen(self) -> $wide {
+                self.into()
+            }
+        }
+    )*};
+}
+
+impl_widen!(u8 => u16, u16 => u32, u32 => u64, u64 => u128, u128 => BigUint);
+
+impl Widen for BigUint {
+    type Wide = BigUint;
+
+    fn widen(self) -> BigUint {
+        self
+    }
+}
+
+/// What a numeric stage does when a result doesn't fit in its type.
+/// To not have to choose, see `widening_square`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Reject the item, see `Pipeline::try_stage`.
+    #[default]
+    Checked,
+    /// Clamp the result to the largest value of the type.
+    Saturating,
+    /// Keep the low bits of the result, like release builds do.
+    Wrapping,
+}
+
+impl Overflow {
+    pub fn mul<N: Integer>(self, lhs: &N, rhs: &N) -> Result<N, OverflowError<N>> {
+        match self {
+            Overflow::Checked => lhs.checked_mul(rhs).ok_or_else(|| OverflowError {
+                lhs: lhs.clone(),
+                rhs: rhs.clone(),
+            }),
+            Overflow::Saturating => Ok(lhs.saturating_mul(rhs)),
+            Overflow::Wrapping => Ok(lhs.wrapping_mul(rhs)),
+        }
+    }
+
+    pub fn square<N: Integer>(self, num: &N) -> Result<N, OverflowError<N>> {
+        self.mul(num, num)
+    }
+}
+
+/// A multiplication whose result doesn't fit in `N`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OverflowError<N> {
+    pub lhs: N,
+    pub rhs: N,
+}
+
+impl<N: Display> Display for OverflowError<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} * {} does not fit in a {}",
+            self.lhs,
+            self.rhs,
+            type_name::<N>().rsplit("::").next().unwrap_or_default()
+        )
+    }
+}
+
+impl<N: Debug + Display> std::error::Error for OverflowError<N> {}
+
+/// A stage squaring integers of type `N`, to be added with `Pipeline::try_stage`
+/// or `Pipeline::try_fan_out`: with `Overflow::Checked`, the items whose square overflows
+/// are reported and dropped.
+pub struct Square<N> {
+    overflow: Overflow,
+    // `fn` keeps the stage `Send` whatever `N` is.
+    num: PhantomData<fn(N) -> N>,
+}
+
+impl<N> Square<N> {
+    pub fn new(overflow: Overflow) -> Self {
+        Square {
+            overflow,
+            num: PhantomData,
+        }
+    }
+}
+
+impl<N> Clone for Square<N> {
+    fn clone(&self) -> Self {
+        Square::new(self.overflow)
+    }
+}
+
+impl<N: Integer> Stage<N, Result<N, OverflowError<N>>> for Square<N> {
+    fn process(&mut self, num: N) -> Result<N, OverflowError<N>> {
+        self.overflow.square(&num)
+    }
+}
+
+/// Square `num` into the wider type, which never overflows.
+pub fn widening_square<N: Widen>(num: N) -> N::Wide {
+    let wide = num.widen();
+    // Wrapping never happens, the wide type is big enough.
+    wide.wrapping_mul(&wide)
+}
+
+/// An unsigned integer of any size, for when even `u128` is too small.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct BigUint {
+    /// Little-endian base 2^32 digits, without trailing zeros, so zero has none.
+    limbs: Vec<u32>,
+}
+
+impl BigUint {
+    pub fn is_zero(&self) -> bool {
+        self.limbs.is_empty()
+    }
+
+    fn trim(&mut self) {
+        while self.limbs.last() == Some(&0) {
+            self.limbs.pop();
+        }
+    }
+
+    /// Divide in place by `divisor`, returning the remainder.
+    fn div_rem(&mut self, divisor: u32) -> u32 {
+        let mut rem = 0u64;
+        for limb in self.limbs.iter_mut().rev() {
+            let acc = (rem << 32) | u64::from(*limb);
+            *limb = (acc / u64::from(divisor)) as u32;
+            rem = acc % u64::from(divisor);
+        }
+        self.trim();
+        rem as u32
+    }
+}
+
+impl Integer for BigUint {
+    /// Schoolbook addition, which never overflows.
+    fn checked_add(&self, rhs: &Self) -> Option<Self> {
+        let (long, short) = if self.limbs.len() >= rhs.limbs.len() {
+            (&self.limbs, &rhs.limbs)
+        } else {
+            (&rhs.limbs, &self.limbs)
+        };
+        let mut limbs = Vec::with_capacity(long.len() + 1);
+        let mut carry = 0u64;
+        for (i, &a) in long.iter().enumerate() {
+            let acc = u64::from(a) + u64::from(short.get(i).copied().unwrap_or(0)) + carry;
+            limbs.push(acc as u32);
+            carry = acc >> 32;
+        }
+        limbs.push(carry as u32);
+        let mut sum = BigUint { limbs };
+        sum.trim();
+        Some(sum)
+    }
+
+    fn checked_mul(&self, rhs: &Self) -> Option<Self> {
+        Some(self.wrapping_mul(rhs))
+    }
+
+    fn saturating_mul(&self, rhs: &Self) -> Self {
+        self.wrapping_mul(rhs)
+    }
+
+    /// Schoolbook multiplication, which never overflows.
+    fn wrapping_mul(&self, rhs: &Self) -> Self {
+        if self.is_zero() || rhs.is_zero() {
+            return BigUint::default();
+        }
+        let mut limbs = vec![0u32; self.limbs.len() + rhs.limbs.len()];
+        for (i, &a) in self.limbs.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, &b) in rhs.limbs.iter().enumerate() {
+                // Can't overflow: (2^32 - 1)^2 + 2 * (2^32 - 1) < 2^64.
+                let acc = u64::from(a) * u64::from(b) + u64::from(limbs[i + j]) + carry;
+                limbs[i + j] = acc as u32;
+                carry = acc >> 32;
+            }
+            limbs[i + rhs.limbs.len()] = carry as u32;
+        }
+        let mut product = BigUint { limbs };
+        product.trim();
+        product
+    }
+}
+
+macro_rules! impl_from {
+    ($($int:ty),*) => {$(
+        impl From<$int> for BigUint {
+            fn from(num: $int) -> Self {
+                let mut num = num as u128;
+                let mut limbs = Vec::new();
+                while num > 0 {
+                    limbs.push(num as u32);
+                    num >>= 32;
+                }
+                BigUint { limbs }
+            }
+        }
+    )*};
+}
+
+impl_from!(u8, u16, u32, u64, u128, usize);
+
+impl Ord for BigUint {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Without trailing zeros, the longer number is the bigger one.
+        self.limbs
+            .len()
+            .cmp(&other.limbs.len())
+            .then_with(|| self.limbs.iter().rev().cmp(other.limbs.iter().rev()))
+    }
+}
+
+impl PartialOrd for BigUint {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Display for BigUint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const CHUNK: u32 = 1_000_000_000;
+        if self.is_zero() {
+            return f.pad_integral(true, "", "0");
+        }
+        // Peel off 9 decimal digits at a time, least significant first.
+        let mut rest = self.clone();
+        let mut chunks = Vec::new();
+        while !rest.is_zero() {
+            chunks.push(rest.div_rem(CHUNK));
+        }
+        let mut chunks = chunks.iter().rev();
+        let mut digits = chunks.next().map(u32::to_string).unwrap_or_default();
+        for chunk in chunks {
+            let _ = write!(digits, "{:09}", chunk);
+        }
+        f.pad_integral(true, "", &digits)
+    }
+}
+
+impl Debug for BigUint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn big_numbers_multiply_and_print_in_decimal() {
+        let max = BigUint::from(u128::MAX);
+        assert_eq!(max.to_string(), u128::MAX.to_string());
+        assert_eq!(
+            max.wrapping_mul(&max).to_string(),
+            "115792089237316195423570985008687907852589419931798687112530834793049593217025"
+        );
+        // Chunks of 9 digits in the middle keep their leading zeros.
+        assert_eq!(BigUint::from(1_000_000_000u64).to_string(), "1000000000");
+        assert_eq!(
+            BigUint::from(1u128 << 96).to_string(),
+            "79228162514264337593543950336"
+        );
+        assert_eq!(BigUint::default().to_string(), "0");
+        assert!(BigUint::default().wrapping_mul(&max).is_zero());
+        assert_eq!(format!("{:>5}", BigUint::from(42u8)), "   42");
+    }
+
+    #[test]
+    fn big_numbers_compare_by_value() {
+        let small = BigUint::from(u64::MAX);
+        let big = BigUint::from(u64::MAX as u128 + 1);
+        assert!(small < big);
+        assert!(big.wrapping_mul(&big) > big);
+        assert_eq!(small.checked_add(&BigUint::from(1u8)), Some(big.clone()));
+        assert_eq!(
+            BigUint::from(u128::MAX).checked_add(&BigUint::from(u128::MAX)),
+            Some(BigUint::from(u128::MAX).wrapping_mul(&BigUint::from(2u8)))
+        );
+        assert_eq!(BigUint::default().checked_add(&big), Some(big.clone()));
+        assert_eq!(BigUint::from(7u8), BigUint::from(7u128));
+    }
+
+    #[test]
+    fn widening_squares_never_overflow() {
+        assert_eq!(widening_square(u8::MAX), 65_025u16);
+        assert_eq!(
+            widening_square(u64::MAX),
+            u64::MAX as u128 * u64::MAX as u128
+        );
+        assert_eq!(
+            widening_square(u128::MAX),
+            BigUint::from(u128::MAX).wrapping_mul(&BigUint::from(u128::MAX))
+        );
+        assert_eq!(widening_square(0u32), 0);
+    }
+
+    #[test]
+    fn overflows_are_handled_by_policy() {
+        // 15 is the largest u8 whose square fits, 16 the smallest whose square doesn't.
+        assert_eq!(Overflow::Checked.square(&15u8), Ok(225));
+        assert_eq!(
+            Overflow::Checked.square(&16u8),
+            Err(OverflowError { lhs: 16, rhs: 16 })
+        );
+        assert_eq!(Overflow::Saturating.square(&16u8), Ok(u8::MAX));
+        assert_eq!(Overflow::Wrapping.square(&16u8), Ok(0));
+        assert_eq!(Overflow::Wrapping.square(&u8::MAX), Ok(1));
+
+        let fits = u64::MAX as u128;
+        let overflows = fits + 1;
+        assert_eq!(Overflow::Checked.square(&fits), Ok(fits * fits));
+        assert!(Overflow::Checked.square(&overflows).is_err());
+        assert_eq!(Overflow::Saturating.square(&overflows), Ok(u128::MAX));
+        assert_eq!(Overflow::Wrapping.square(&overflows), Ok(0));
+        assert_eq!(Overflow::Wrapping.square(&u128::MAX), Ok(1));
+    }
+
+    #[test]
+    fn overflow_errors_name_the_type() {
+        let err = Overflow::Checked.square(&16u8).unwrap_err();
+        assert_eq!(err.to_string(), "16 * 16 does not fit in a u8");
+    }
+}
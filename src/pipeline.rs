@@ -0,0 +1,516 @@
+use std::fmt::{Debug, Display};
+use std::sync::mpsc::{channel, Receiver, TryIter};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::batch::{spawn_batcher, spawn_unbatcher, Batch};
+use crate::cancel::CancellationToken;
+use crate::error::{panic_message, PipelineError, StageError, StageReport};
+use crate::event;
+use crate::fan_out::{spawn_fan_out, FanOut, WorkerPool};
+use crate::link::{Envelope, Inbound, LinkStats};
+use crate::log::{self, ItemId, Level, Logger, Silent};
+use crate::metrics::Metrics;
+use crate::sink::{spawn_sink, Sink};
+use crate::source::Source;
+use crate::stage::{Stage, StageConfig};
+use crate::watchdog::{self, spawn_watchdog, StageStatus, Stuck, Watchdog, WatchdogThread};
+use crate::window::{spawn_windower, Aggregate, Window, Windowed};
+use crate::wiring::{lock, pass, reject, spawn_worker, Route, Wiring};
+
+/// Deferred wiring of everything upstream of (and including) the current stage.
+/// Calling it spawns the stage threads and hands back the outbound channel of the last one.
+type Build<T> = Box<dyn FnOnce(&Wiring) -> Result<Inbound<Envelope<T>>, PipelineError> + Send>;
+
+/// A pipeline is a series of stages connected by channels.
+/// `Pipeline<T>` describes the stages built so far, `T` being the type of the values
+/// coming out of the last one. Nothing runs until `spawn` is called.
+///
+/// Every stage boundary is typed: a stage can only be appended if its input type
+/// is the output type of the previous one, so mis-wired pipelines are rejected
+/// by the compiler instead of blowing up at runtime.
+pub struct Pipeline<T> {
+    build: Build<T>,
+    logger: Arc<dyn Logger>,
+    watchdog: Option<Watchdog>,
+}
+
+impl<T: Send + Debug + 'static> Pipeline<T> {
+    /// Start a pipeline from a source, which is drained until it runs out of items,
+    /// until nobody downstream is listening anymore or until the pipeline is cancelled.
+    pub fn source(config: impl Into<StageConfig>, source: Source<T>) -> Self {
+        let config = config.into();
+        let Source {
+            mut items,
+            interval,
+        } = source;
+        Pipeline {
+            build: Box::new(move |wiring| {
+                let (tx, rx) = wiring.link(config.name.clone(), config.link);
+                wiring.spawn(&config, move |ctx| {
+                    let mut next_id = 0;
+                    let mut next_at = Instant::now();
+                    while !ctx.token.is_cancelled() {
+                        if let Some(interval) = interval {
+                            if !ctx.token.sleep_until(next_at) {
+                                break;
+                            }
+                            // Without catching up on the time spent blocked downstream.
+                            next_at = next_at.max(Instant::now()) + interval;
+                        }
+                        // Items are numbered in the order they come out of the source,
+                        // and the source logs about the item it is about to emit.
+                        let id = ItemId(next_id);
+                        log::enter_item(Some(id));
+                        let next = ctx.metrics.track(
+                            || ctx.guard(None, || items.next()),
+                            |next| match next {
+                                Ok(Some(None)) => None,
+                                next => Some(matches!(next, Ok(Some(Some(_))))),
+                            },
+                        );
+                        let item = match next? {
+                            Some(Some(item)) => item,
+                            Some(None) => break,
+                            // The panic was reported, but there is no telling whether
+                            // an iterator that panicked would ever return an item again.
+                            None => break,
+                        };
+                        next_id += 1;
+                        event!(Level::Trace, "emitting {:?}", item);
+                        if !ctx.forward(&tx, Envelope { id, item }) {
+                            break;
+                        }
+                    }
+                    Ok(())
+                })?;
+                Ok(rx)
+            }),
+            logger: Arc::new(Silent),
+            watchdog: None,
+        }
+    }
+
+    /// Send the log events of every stage to `logger`. Nothing is logged by default.
+    pub fn logger(mut self, logger: impl Logger) -> Self {
+        self.logger = Arc::new(logger);
+        self
+    }
+
+    /// Keep an eye on the stages once the pipeline is cancelled,
+    /// reporting the ones that don't stop in time. There is no watchdog by default.
+    /// Its thread is stopped and joined by `join`, `shutdown` and `shutdown_timeout`.
+    pub fn watchdog(mut self, watchdog: Watchdog) -> Self {
+        self.watchdog = Some(watchdog);
+        self
+    }
+
+    /// Append a stage, run on its own thread, to the pipeline.
+    pub fn stage<U, S>(self, config: impl Into<StageConfig>, stage: S) -> Pipeline<U>
+    where
+        U: Send + 'static,
+        S: Stage<T, U>,
+    {
+        self.stage_with(config.into(), stage, pass::<U>())
+    }
+
+    /// Append a fallible stage. Items for which it returns an error are reported
+    /// (see `StageConfig::dead_letters`) and dropped, while the others continue downstream.
+    pub fn try_stage<U, E, S>(self, config: impl Into<StageConfig>, stage: S) -> Pipeline<U>
+    where
+        U: Send + 'static,
+        E: Display + 'static,
+        S: Stage<T, Result<U, E>>,
+    {
+        self.stage_with(config.into(), stage, reject::<U, E>())
+    }
+
+    /// Distribute the values over several workers running in parallel, each one with its own
+    /// clone of `stage`, then merge their outputs back into a single channel, in the order
+    /// they come if `FanOut::ordered` is set, in the order they are done otherwise.
+    /// Workers are named after the stage with their index appended, e.g. `square-0`,
+    /// and can be added or retired while the pipeline runs, see `PipelineHandle::pool`.
+    pub fn fan_out<U, S>(
+        self,
+        config: impl Into<StageConfig>,
+        fan_out: FanOut<T>,
+        stage: S,
+    ) -> Pipeline<U>
+    where
+        U: Send + 'static,
+        S: Stage<T, U> + Clone,
+    {
+        self.fan_out_with(config.into(), fan_out, stage, pass::<U>())
+    }
+
+    /// Same as `fan_out`, with fallible workers as in `try_stage`.
+    pub fn try_fan_out<U, E, S>(
+        self,
+        config: impl Into<StageConfig>,
+        fan_out: FanOut<T>,
+        stage: S,
+    ) -> Pipeline<U>
+    where
+        U: Send + 'static,
+        E: Display + 'static,
+        S: Stage<T, Result<U, E>> + Clone,
+    {
+        self.fan_out_with(config.into(), fan_out, stage, reject::<U, E>())
+    }
+
+    /// Group the values into batches, e.g. so the next stage can process them in chunks
+    /// instead of paying for the link between each of them. Each batch goes by the id
+    /// of its first value. See `unbatch` to go back to single values.
+    pub fn batch(self, config: impl Into<StageConfig>, batch: Batch<T>) -> Pipeline<Vec<T>> {
+        let config = config.into();
+        let upstream = self.build;
+        Pipeline {
+            build: Box::new(move |wiring| {
+                let inbound = upstream(wiring)?;
+                let (tx, rx) = wiring.link(config.name.clone(), config.link);
+                spawn_batcher(wiring, &config, batch, inbound, tx)?;
+                Ok(rx)
+            }),
+            logger: self.logger,
+            watchdog: self.watchdog,
+        }
+    }
+
+    /// Aggregate the values over windows of time, e.g. the sum of the values of every second,
+    /// sending each window downstream once it is closed, in the order they end.
+    /// Values coming after all their windows were closed are reported
+    /// (see `StageConfig::dead_letters`) and dropped.
+    pub fn window<A>(
+        self,
+        config: impl Into<StageConfig>,
+        window: Window<T>,
+        aggregate: A,
+    ) -> Pipeline<Windowed<A::Output>>
+    where
+        A: Aggregate<T>,
+    {
+        let config = config.into();
+        let upstream = self.build;
+        Pipeline {
+            build: Box::new(move |wiring| {
+                let inbound = upstream(wiring)?;
+                let (tx, rx) = wiring.link(config.name.clone(), config.link);
+                spawn_windower(wiring, &config, window, aggregate, inbound, tx)?;
+                Ok(rx)
+            }),
+            logger: self.logger,
+            watchdog: self.watchdog,
+        }
+    }
+
+    /// End the pipeline with a sink, run on its own thread, consuming every value.
+    /// Once upstream is done, the sink's output is the one and only value coming out,
+    /// e.g. to keep a handle on the running pipeline. See `run` otherwise.
+    pub fn sink<S>(self, config: impl Into<StageConfig>, sink: S) -> Pipeline<S::Output>
+    where
+        S: Sink<T>,
+    {
+        let config = config.into();
+        let upstream = self.build;
+        Pipeline {
+            build: Box::new(move |wiring| {
+                let inbound = upstream(wiring)?;
+                let (tx, rx) = wiring.link(config.name.clone(), config.link);
+                spawn_sink(wiring, &config, sink, inbound, tx)?;
+                Ok(rx)
+            }),
+            logger: self.logger,
+            watchdog: self.watchdog,
+        }
+    }
+
+    /// Run the pipeline to completion into `sink`, e.g. `sink::Collect`, returning its output
+    /// once every stage has exited. The sink stage is called `sink`.
+    /// Fails with the first stage that failed, if any.
+    pub fn run<S>(self, sink: S) -> Result<S::Output, PipelineError>
+    where
+        S: Sink<T>,
+    {
+        let pipeline = self.sink("sink", sink).spawn()?;
+        let output = pipeline.recv();
+        for report in pipeline.join() {
+            report.result?;
+        }
+        output.ok_or(PipelineError::Cancelled)
+    }
+
+    fn stage_with<Out, U, S>(
+        self,
+        config: StageConfig,
+        stage: S,
+        route: Route<Out, U>,
+    ) -> Pipeline<U>
+    where
+        Out: 'static,
+        U: Send + 'static,
+        S: Stage<T, Out>,
+    {
+        let upstream = self.build;
+        Pipeline {
+            build: Box::new(move |wiring| {
+                let inbound = upstream(wiring)?;
+                let (tx, rx) = wiring.link(config.name.clone(), config.link);
+                spawn_worker(wiring, &config, stage, route, inbound, tx)?;
+                Ok(rx)
+            }),
+            logger: self.logger,
+            watchdog: self.watchdog,
+        }
+    }
+
+    fn fan_out_with<Out, U, S>(
+        self,
+        config: StageConfig,
+        fan_out: FanOut<T>,
+        stage: S,
+        route: Route<Out, U>,
+    ) -> Pipeline<U>
+    where
+        Out: 'static,
+        U: Send + 'static,
+        S: Stage<T, Out> + Clone,
+    {
+        let upstream = self.build;
+        Pipeline {
+            build: Box::new(move |wiring| {
+                let inbound = upstream(wiring)?;
+                spawn_fan_out(wiring, &config, fan_out, stage, route, inbound)
+            }),
+            logger: self.logger,
+            watchdog: self.watchdog,
+        }
+    }
+
+    /// Spawn every stage and return the handle to the running pipeline.
+    /// If any stage fails to spawn, the stages already running are shut down
+    /// and the spawn error is returned.
+    pub fn spawn(self) -> Result<PipelineHandle<T>, PipelineError> {
+        spawn_with(self.logger, self.watchdog, self.build)
+    }
+
+    /// A pipeline starting from a link wired elsewhere, e.g. the input of a topology node.
+    pub(crate) fn from_inbound(inbound: Inbound<Envelope<T>>) -> Self {
+        Pipeline {
+            build: Box::new(move |_| Ok(inbound)),
+            logger: Arc::new(Silent),
+            watchdog: None,
+        }
+    }
+
+    /// Spawn the stages of the pipeline, returning the link out of the last one.
+    pub(crate) fn wire(self, wiring: &Wiring) -> Result<Inbound<Envelope<T>>, PipelineError> {
+        (self.build)(wiring)
+    }
+}
+
+/// Spawn the stages wired by `build` and return the handle to the running pipeline,
+/// reading from the link `build` returns. See `Pipeline::spawn`.
+pub(crate) fn spawn_with<T, F>(
+    logger: Arc<dyn Logger>,
+    watchdog: Option<Watchdog>,
+    build: F,
+) -> Result<PipelineHandle<T>, PipelineError>
+where
+    F: FnOnce(&Wiring) -> Result<Inbound<Envelope<T>>, PipelineError>,
+{
+    let (errors_tx, errors_rx) = channel();
+    let wiring = Wiring::new(CancellationToken::new(), errors_tx, logger);
+    let result = build(&wiring).and_then(|output| {
+        let watchdog = match watchdog {
+            Some(watchdog) => {
+                let (token, probes) = (wiring.token.clone(), wiring.probes.clone());
+                let thread =
+                    spawn_watchdog(watchdog, token, probes, wiring.logger()).map_err(|source| {
+                        PipelineError::Spawn {
+                            stage: "watchdog".to_owned(),
+                            source,
+                        }
+                    })?;
+                Some(thread)
+            }
+            None => None,
+        };
+        Ok((output, watchdog))
+    });
+    let handle = PipelineHandle {
+        output: None,
+        errors: errors_rx,
+        wiring,
+        watchdog: None,
+    };
+    match result {
+        Ok((output, watchdog)) => Ok(PipelineHandle {
+            output: Some(output),
+            watchdog,
+            ..handle
+        }),
+        Err(err) => {
+            handle.shutdown();
+            Err(err)
+        }
+    }
+}
+
+impl<T: Send + Debug + 'static> Pipeline<Vec<T>> {
+    /// Split the batches coming out of `batch`, or of any stage producing `Vec`s,
+    /// sending their values downstream one by one. Each value goes by the id of its batch.
+    pub fn unbatch(self, config: impl Into<StageConfig>) -> Pipeline<T> {
+        let config = config.into();
+        let upstream = self.build;
+        Pipeline {
+            build: Box::new(move |wiring| {
+                let inbound = upstream(wiring)?;
+                let (tx, rx) = wiring.link(config.name.clone(), config.link);
+                spawn_unbatcher(wiring, &config, inbound, tx)?;
+                Ok(rx)
+            }),
+            logger: self.logger,
+            watchdog: self.watchdog,
+        }
+    }
+}
+
+/// Owns every thread of a running pipeline.
+///
+/// The pipeline stops by itself once the source is exhausted and every value made it through,
+/// or earlier when `cancel` is called, in which case values still in flight are dropped.
+/// Either way, `join` waits for every stage thread to exit and reports how each one ended.
+pub struct PipelineHandle<T> {
+    /// Only missing while a pipeline that failed to spawn is being shut down.
+    output: Option<Inbound<Envelope<T>>>,
+    errors: Receiver<StageError>,
+    wiring: Wiring,
+    /// Joined along with the stages, see `Pipeline::watchdog`.
+    watchdog: Option<WatchdogThread>,
+}
+
+impl<T> PipelineHandle<T> {
+    /// Block until the next output value, `None` once the last stage has stopped.
+    pub fn recv(&self) -> Option<T> {
+        self.output.as_ref()?.recv().map(|envelope| envelope.item)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(|| self.recv())
+    }
+
+    /// The errors reported by the stages so far, e.g. panics that were caught.
+    pub fn errors(&self) -> TryIter<'_, StageError> {
+        self.errors.try_iter()
+    }
+
+    /// What went through each link between stages so far, in the order they were created.
+    pub fn links(&self) -> Vec<LinkStats> {
+        lock(&self.wiring.links)
+            .iter()
+            .map(|link| link.stats())
+            .collect()
+    }
+
+    /// The live metrics of every stage and link, which can be snapshotted at any time,
+    /// or served for Prometheus to scrape, see `MetricsServer`.
+    pub fn metrics(&self) -> Metrics {
+        self.wiring.metrics()
+    }
+
+    /// The workers of the fan-out stage called `name`.
+    pub fn pool(&self, name: &str) -> Option<WorkerPool> {
+        lock(&self.wiring.pools)
+            .iter()
+            .find(|pool| pool.name() == name)
+            .cloned()
+    }
+
+    /// A token that stops this pipeline when cancelled, usable from any thread.
+    pub fn token(&self) -> CancellationToken {
+        self.wiring.token.clone()
+    }
+
+    /// Ask every stage to stop, without waiting for them to do so.
+    pub fn cancel(&self) {
+        self.wiring.token.cancel();
+    }
+
+    /// The stages whose thread has not exited yet, in the order they were spawned.
+    pub fn running(&self) -> Vec<String> {
+        lock(&self.wiring.threads)
+            .iter()
+            .filter(|(_, thread)| !thread.is_finished())
+            .map(|(stage, _)| stage.clone())
+            .collect()
+    }
+
+    /// What every stage is doing right now, including the ones that have stopped,
+    /// in the order they were spawned.
+    pub fn status(&self) -> Vec<StageStatus> {
+        lock(&self.wiring.probes)
+            .iter()
+            .map(|probe| probe.status())
+            .collect()
+    }
+
+    /// Block until every stage thread has exited, in the order the stages were spawned.
+    pub fn join(self) -> Vec<StageReport> {
+        // Nobody is going to read the output anymore.
+        drop(self.output);
+        let mut reports = Vec::new();
+        // Fan-out stages may still be adding workers while we wait.
+        loop {
+            let next = {
+                let mut threads = lock(&self.wiring.threads);
+                (!threads.is_empty()).then(|| threads.remove(0))
+            };
+            let Some((stage, thread)) = next else {
+                break;
+            };
+            let result = thread.join().unwrap_or_else(|payload| {
+                Err(PipelineError::Panicked {
+                    stage: stage.clone(),
+                    message: panic_message(payload.as_ref()),
+                })
+            });
+            reports.push(StageReport { stage, result });
+        }
+        if let Some(watchdog) = self.watchdog {
+            watchdog.join();
+        }
+        reports
+    }
+
+    /// Cancel the pipeline and wait for all its stages to exit.
+    pub fn shutdown(self) -> Vec<StageReport> {
+        self.cancel();
+        self.join()
+    }
+
+    /// Same as `shutdown`, giving up on the stages that are still running after `timeout`.
+    /// Their threads are left behind, and reported with what they are blocked on.
+    pub fn shutdown_timeout(self, timeout: Duration) -> Result<Vec<StageReport>, Stuck> {
+        self.cancel();
+        let deadline = Instant::now() + timeout;
+        loop {
+            let running = watchdog::running(&self.wiring.probes);
+            if running.is_empty() {
+                return Ok(self.join());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                // The stuck stages are reported here, rather than by the watchdog as well.
+                if let Some(watchdog) = self.watchdog {
+                    watchdog.join();
+                }
+                return Err(Stuck {
+                    waited: timeout,
+                    stages: running,
+                });
+            }
+            thread::sleep((deadline - now).min(Duration::from_millis(10)));
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::error::{PipelineError, StageErrorKind};
+use crate::event;
+use crate::link::{Envelope, Inbound, Outbound};
+use crate::log::{self, Level};
+use crate::stage::StageConfig;
+use crate::wiring::{lock, Wiring};
+
+/// Picks one of the branches of a rule for an item, as an index into them, if any.
+type Pick<T> = Box<dyn Fn(&T) -> Option<usize> + Send + Sync>;
+
+struct Rule<T> {
+    branches: Vec<String>,
+    pick: Pick<T>,
+}
+
+/// Which branch of a router each item goes to, see `Router`.
+/// Rules are tried in the order they were added, and the first one picking a branch wins.
+pub struct Rules<T> {
+    rules: Vec<Rule<T>>,
+}
+
+impl<T> Rules<T> {
+    pub fn new() -> Self {
+        Rules { rules: Vec::new() }
+    }
+
+    /// Send the items for which `predicate` returns `true` to `branch`.
+    pub fn when<P>(mut self, branch: &str, predicate: P) -> Self
+    where
+        P: Fn(&T) -> bool + Send + Sync + 'static,
+    {
+        self.rules.push(Rule {
+            branches: vec![branch.to_owned()],
+            pick: Box::new(move |item| predicate(item).then_some(0)),
+        });
+        self
+    }
+
+    /// Send the items to the branch their key goes with in `branches`, e.g.
+    /// `.by_key(|order: &Order| order.region, [(Region::Eu, "eu"), (Region::Us, "us")])`.
+    /// Items with any other key are left to the next rules.
+    pub fn by_key<'a, K, F>(
+        mut self,
+        key: F,
+        branches: impl IntoIterator<Item = (K, &'a str)>,
+    ) -> Self
+    where
+        K: Hash + Eq + Send + Sync + 'static,
+        F: Fn(&T) -> K + Send + Sync + 'static,
+    {
+        let mut names = Vec::new();
+        let mut keys = HashMap::new();
+        for (k, branch) in branches {
+            let index = match names.iter().position(|name| name == branch) {
+                Some(index) => index,
+                None => {
+                    names.push(branch.to_owned());
+                    names.len() - 1
+                }
+            };
+            keys.insert(k, index);
+        }
+        self.rules.push(Rule {
+            branches: names,
+            pick: Box::new(move |item| keys.get(&key(item)).copied()),
+        });
+        self
+    }
+
+    /// Every branch the rules send items to, once each, in the order they first appear.
+    pub fn branches(&self) -> Vec<String> {
+        let mut branches: Vec<String> = Vec::new();
+        for branch in self.rules.iter().flat_map(|rule| &rule.branches) {
+            if !branches.contains(branch) {
+                branches.push(branch.clone());
+            }
+        }
+        branches
+    }
+
+    /// The branch `item` goes to, by name, if any rule picks one.
+    fn route(&self, item: &T) -> Option<&str> {
+        self.rules.iter().find_map(|rule| {
+            let branch = (rule.pick)(item)?;
+            Some(rule.branches[branch].as_str())
+        })
+    }
+}
+
+impl<T> Default for Rules<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Shared<T> {
+    rules: Mutex<Arc<Rules<T>>>,
+    /// Bumped every time the rules are replaced, so the router only has to look at them then.
+    version: AtomicU64,
+}
+
+/// Sends each item to one of several named branches, depending on what it holds,
+/// see `Topology::router`. Items no rule picks a branch for are unmatched.
+///
+/// A clone of the router kept aside can replace its rules while the pipeline is running,
+/// the items coming next being routed by the new ones.
+pub struct Router<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Router<T> {
+    pub fn new(rules: Rules<T>) -> Self {
+        Router {
+            shared: Arc::new(Shared {
+                rules: Mutex::new(Arc::new(rules)),
+                version: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Route the items coming next by `rules` instead. The branches of a router are set once
+    /// and for all when it is added to a topology: items for any other branch are unmatched.
+    pub fn replace(&self, rules: Rules<T>) {
+        *lock(&self.shared.rules) = Arc::new(rules);
+        self.shared.version.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// The branches of the current rules, see `Rules::branches`.
+    pub fn branches(&self) -> Vec<String> {
+        self.rules().branches()
+    }
+
+    fn rules(&self) -> Arc<Rules<T>> {
+        lock(&self.shared.rules).clone()
+    }
+}
+
+impl<T> Clone for Router<T> {
+    fn clone(&self) -> Self {
+        Router {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+/// A branch of a running router, whose sending end is gone once nobody is listening anymore.
+pub(crate) struct Route<T> {
+    pub(crate) name: String,
+    pub(crate) outbound: Option<Outbound<Envelope<T>>>,
+}
+
+/// Route every item of `inbound` to one of `branches`, or to `unmatched` if no rule picks
+/// any of them. Unmatched items go to the stage's dead letters if `unmatched` is `None`.
+pub(crate) fn spawn_router<T>(
+    wiring: &Wiring,
+    config: &StageConfig,
+    router: Router<T>,
+    inbound: Inbound<Envelope<T>>,
+    mut branches: Vec<Route<T>>,
+    mut unmatched: Option<Outbound<Envelope<T>>>,
+) -> Result<(), PipelineError>
+where
+    T: Send + Debug + 'static,
+{
+    let dead_letters = unmatched.is_none();
+    wiring.spawn(config, move |ctx| {
+        let mut version = router.shared.version.load(Ordering::SeqCst);
+        let mut rules = router.rules();
+        while let Some(Envelope { id, item }) = inbound.recv() {
+            log::enter_item(Some(id));
+            let latest = router.shared.version.load(Ordering::SeqCst);
+            if latest != version {
+                event!(Level::Debug, "routing by new rules");
+                version = latest;
+                rules = router.rules();
+            }
+            let branch = rules
+                .route(&item)
+                .and_then(|name| branches.iter_mut().find(|branch| branch.name == name));
+            let outbound = match branch {
+                Some(branch) => &mut branch.outbound,
+                None if dead_letters => {
+                    ctx.dead_letter(&format!("{:?}", item), StageErrorKind::Unrouted);
+                    continue;
+                }
+                None => &mut unmatched,
+            };
+            // The items for a branch that is gone are lost, rather than
+            // going to another branch they were not meant for.
+            let sent = outbound
+                .as_ref()
+                .map(|link| ctx.forward(link, Envelope { id, item }));
+            if sent == Some(false) {
+                *outbound = None;
+            }
+            let open = branches.iter().any(|branch| branch.outbound.is_some());
+            if !open && unmatched.is_none() {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
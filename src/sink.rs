@@ -0,0 +1,244 @@
+use std::fmt::{Debug, Display};
+use std::io::{self, Write};
+use std::ops::ControlFlow;
+
+use crate::error::PipelineError;
+use crate::event;
+use crate::link::{Envelope, Inbound, Outbound};
+use crate::log::{self, ItemId, Level};
+use crate::stage::StageConfig;
+use crate::wiring::Wiring;
+
+/// The end of a pipeline: consumes every value coming out of the last stage,
+/// and turns them into a single output once there are no more.
+/// Sinks run on their own thread, so they must be `Send + 'static`.
+pub trait Sink<T>: Send + 'static {
+    type Output: Send + Debug + 'static;
+
+    /// Take in the next value. `Break` stops the sink early,
+    /// e.g. because it has all it needs or can't go on.
+    fn consume(&mut self, item: T) -> ControlFlow<()>;
+
+    fn finish(self) -> Self::Output;
+}
+
+/// Collect every value into a `Vec`, in the order they come.
+pub struct Collect<T> {
+    items: Vec<T>,
+}
+
+impl<T> Collect<T> {
+    pub fn new() -> Self {
+        Collect { items: Vec::new() }
+    }
+}
+
+impl<T> Default for Collect<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Send + Debug + 'static> Sink<T> for Collect<T> {
+    type Output = Vec<T>;
+
+    fn consume(&mut self, item: T) -> ControlFlow<()> {
+        self.items.push(item);
+        ControlFlow::Continue(())
+    }
+
+    fn finish(self) -> Vec<T> {
+        self.items
+    }
+}
+
+/// Fold every value into an accumulator, e.g. `Fold::new(0, |sum, num| sum + num)`.
+/// If `f` panics, the accumulator is lost with it: the sink stops there,
+/// and its output is `None` even if the panic is caught.
+pub struct Fold<A, F> {
+    /// Only missing if `f` panicked.
+    acc: Option<A>,
+    f: F,
+}
+
+impl<A, F> Fold<A, F> {
+    pub fn new(init: A, f: F) -> Self {
+        Fold { acc: Some(init), f }
+    }
+}
+
+impl<T, A, F> Sink<T> for Fold<A, F>
+where
+    A: Send + Debug + 'static,
+    F: FnMut(A, T) -> A + Send + 'static,
+{
+    type Output = Option<A>;
+
+    fn consume(&mut self, item: T) -> ControlFlow<()> {
+        match self.acc.take() {
+            Some(acc) => {
+                self.acc = Some((self.f)(acc, item));
+                ControlFlow::Continue(())
+            }
+            None => ControlFlow::Break(()),
+        }
+    }
+
+    fn finish(self) -> Option<A> {
+        self.acc
+    }
+}
+
+/// Fold every value into the first one, e.g. `Reduce::new(u64::max)`.
+/// The output is `None` if there were no values at all.
+pub struct Reduce<T, F> {
+    acc: Option<T>,
+    f: F,
+}
+
+impl<T, F> Reduce<T, F> {
+    pub fn new(f: F) -> Self {
+        Reduce { acc: None, f }
+    }
+}
+
+impl<T, F> Sink<T> for Reduce<T, F>
+where
+    T: Send + Debug + 'static,
+    F: FnMut(T, T) -> T + Send + 'static,
+{
+    type Output = Option<T>;
+
+    fn consume(&mut self, item: T) -> ControlFlow<()> {
+        self.acc = Some(match self.acc.take() {
+            Some(acc) => (self.f)(acc, item),
+            None => item,
+        });
+        ControlFlow::Continue(())
+    }
+
+    fn finish(self) -> Option<T> {
+        self.acc
+    }
+}
+
+/// Write every value to `writer` with `write`, e.g. to a file or a socket.
+/// The sink stops at the first error, which is its output. Otherwise, it flushes
+/// the writer once there are no more values and hands it back.
+pub struct WriteTo<W, F> {
+    writer: W,
+    write: F,
+    error: Option<io::Error>,
+}
+
+impl<W, F> WriteTo<W, F> {
+    pub fn new(writer: W, write: F) -> Self {
+        WriteTo {
+            writer,
+            write,
+            error: None,
+        }
+    }
+}
+
+impl<W: Write, T: Display> WriteTo<W, fn(&mut W, T) -> io::Result<()>> {
+    /// Write every value on its own line, as displayed.
+    pub fn lines(writer: W) -> Self {
+        WriteTo::new(writer, |writer, item| writeln!(writer, "{}", item))
+    }
+}
+
+impl<T, W, F> Sink<T> for WriteTo<W, F>
+where
+    W: Write + Send + Debug + 'static,
+    F: FnMut(&mut W, T) -> io::Result<()> + Send + 'static,
+{
+    type Output = io::Result<W>;
+
+    fn consume(&mut self, item: T) -> ControlFlow<()> {
+        match (self.write)(&mut self.writer, item) {
+            Ok(()) => ControlFlow::Continue(()),
+            Err(err) => {
+                self.error = Some(err);
+                ControlFlow::Break(())
+            }
+        }
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        match self.error {
+            Some(err) => Err(err),
+            None => self.writer.flush().map(|()| self.writer),
+        }
+    }
+}
+
+/// Hand every value to a callback as it comes.
+pub struct ForEach<F> {
+    f: F,
+}
+
+impl<F> ForEach<F> {
+    pub fn new(f: F) -> Self {
+        ForEach { f }
+    }
+}
+
+impl<T, F> Sink<T> for ForEach<F>
+where
+    F: FnMut(T) + Send + 'static,
+{
+    type Output = ();
+
+    fn consume(&mut self, item: T) -> ControlFlow<()> {
+        (self.f)(item);
+        ControlFlow::Continue(())
+    }
+
+    fn finish(self) {}
+}
+
+/// Run `sink` on its own thread, consuming `inbound` until upstream is done,
+/// then send its output downstream.
+pub(crate) fn spawn_sink<T, S>(
+    wiring: &Wiring,
+    config: &StageConfig,
+    mut sink: S,
+    inbound: Inbound<Envelope<T>>,
+    outbound: Outbound<Envelope<S::Output>>,
+) -> Result<(), PipelineError>
+where
+    T: Send + Debug + 'static,
+    S: Sink<T>,
+{
+    wiring.spawn(config, move |ctx| {
+        let mut last = None;
+        while let Some(Envelope { id, item }) = inbound.recv() {
+            log::enter_item(Some(id));
+            last = Some(id);
+            event!(Level::Trace, "consuming {:?}", item);
+            let flow = ctx.metrics.track(
+                || ctx.guard(None, || sink.consume(item)),
+                |flow| Some(matches!(flow, Ok(Some(_)))),
+            )?;
+            if let Some(ControlFlow::Break(())) = flow {
+                event!(Level::Debug, "stopped consuming");
+                break;
+            }
+        }
+        // Let upstream know right away that nobody is listening anymore.
+        drop(inbound);
+        if ctx.token.is_cancelled() {
+            return Ok(());
+        }
+        // The output is logged against the last item that went into it.
+        log::enter_item(last);
+        let Some(output) = ctx.guard(None, || sink.finish())? else {
+            return Ok(());
+        };
+        event!(Level::Trace, "output {:?}", output);
+        let id = last.unwrap_or(ItemId(0));
+        ctx.forward(&outbound, Envelope { id, item: output });
+        Ok(())
+    })
+}
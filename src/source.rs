@@ -0,0 +1,79 @@
+use std::io::{self, BufRead};
+use std::time::Duration;
+
+/// Where the items of a pipeline come from, see `Pipeline::source`.
+/// The pipeline stops by itself once the source runs out of items,
+/// so the consumer doesn't have to know when to stop.
+pub struct Source<T> {
+    pub(crate) items: Box<dyn Iterator<Item = T> + Send>,
+    /// The minimum time between two items, if rate limited.
+    pub(crate) interval: Option<Duration>,
+}
+
+impl<T: 'static> Source<T> {
+    /// Emit the items of anything that can be iterated over:
+    /// ranges, step sequences like `(0..).step_by(3)`, vectors, etc.
+    pub fn new<I>(items: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: Send + 'static,
+    {
+        Source {
+            items: Box::new(items.into_iter()),
+            interval: None,
+        }
+    }
+
+    /// Call `generator` until it returns `None`.
+    pub fn from_fn<F>(generator: F) -> Self
+    where
+        F: FnMut() -> Option<T> + Send + 'static,
+    {
+        Source::new(std::iter::from_fn(generator))
+    }
+
+    /// Stop after `n` items.
+    pub fn take(self, n: usize) -> Self {
+        self.map_items(|items| Box::new(items.take(n)))
+    }
+
+    /// Stop at the first item for which `predicate` returns `true`, without emitting it.
+    pub fn stop_when<P>(self, mut predicate: P) -> Self
+    where
+        P: FnMut(&T) -> bool + Send + 'static,
+    {
+        self.map_items(|items| Box::new(items.take_while(move |item| !predicate(item))))
+    }
+
+    /// Emit at most `per_second` items per second, evenly spaced.
+    pub fn rate_limit(mut self, per_second: u32) -> Self {
+        assert!(
+            per_second > 0,
+            "a source must emit at least one item per second"
+        );
+        self.interval = Some(Duration::from_secs(1) / per_second);
+        self
+    }
+
+    fn map_items<F>(self, f: F) -> Self
+    where
+        F: FnOnce(Box<dyn Iterator<Item = T> + Send>) -> Box<dyn Iterator<Item = T> + Send>,
+    {
+        Source {
+            items: f(self.items),
+            interval: self.interval,
+        }
+    }
+}
+
+impl Source<io::Result<String>> {
+    /// Emit the lines of `reader`, e.g. a `BufReader` over a file.
+    /// Read errors are emitted as well: a `try_stage` with `|line| line` right after
+    /// reports them and passes the lines on.
+    pub fn lines<R>(reader: R) -> Self
+    where
+        R: BufRead + Send + 'static,
+    {
+        Source::new(reader.lines())
+    }
+}
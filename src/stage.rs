@@ -0,0 +1,96 @@
+use std::sync::mpsc::Sender;
+
+use crate::error::StageError;
+use crate::link::{Backpressure, LinkConfig};
+
+/// A single step of a pipeline.
+/// A stage receives a value from upstream, performs some function on it
+/// and produces the value that is sent downstream.
+/// Stages run on their own thread, so they must be `Send + 'static`.
+pub trait Stage<In, Out>: Send + 'static {
+    fn process(&mut self, input: In) -> Out;
+}
+
+/// Any closure (or plain function) of the right shape can be plugged in as a stage.
+impl<In, Out, F> Stage<In, Out> for F
+where
+    F: FnMut(In) -> Out + Send + 'static,
+{
+    fn process(&mut self, input: In) -> Out {
+        self(input)
+    }
+}
+
+/// What to do when a stage panics while processing an item.
+/// Either way, the panic is reported on the pipeline's error channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Drop the offending item and keep the stage running.
+    /// A source stops instead, as if it had run out of items.
+    Restart,
+    /// Cancel the whole pipeline.
+    #[default]
+    FailPipeline,
+}
+
+/// How a stage is set up: its name, used for its thread and in error reports,
+/// and its options. A plain `&str` converts into a config with default options.
+#[derive(Clone, Debug)]
+pub struct StageConfig {
+    pub(crate) name: String,
+    pub(crate) on_panic: PanicPolicy,
+    pub(crate) dead_letters: Option<Sender<StageError>>,
+    pub(crate) link: LinkConfig,
+    pub(crate) describe_items: bool,
+}
+
+impl StageConfig {
+    pub fn new(name: &str) -> Self {
+        StageConfig {
+            name: name.to_owned(),
+            on_panic: PanicPolicy::default(),
+            dead_letters: None,
+            link: LinkConfig::default(),
+            describe_items: false,
+        }
+    }
+
+    pub fn on_panic(mut self, policy: PanicPolicy) -> Self {
+        self.on_panic = policy;
+        self
+    }
+
+    /// Send the items rejected by a fallible stage, or too late for a windowing stage,
+    /// to their own channel instead of the pipeline's error channel.
+    pub fn dead_letters(mut self, dead_letters: Sender<StageError>) -> Self {
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+
+    /// Describe every item before it goes into the stage, so that a panic is reported
+    /// with the item that caused it rather than only its id. This formats each item
+    /// with `Debug`, which fallible stages do anyway.
+    pub fn describe_items(mut self) -> Self {
+        self.describe_items = true;
+        self
+    }
+
+    /// Bound the link(s) the stage sends into. Links are unbounded by default.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "a link holds at least one item");
+        self.link.capacity = Some(capacity);
+        self
+    }
+
+    /// What the stage does when a bounded link it sends into is full.
+    pub fn backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.link.backpressure = backpressure;
+        self
+    }
+}
+
+impl From<&str> for StageConfig {
+    fn from(name: &str) -> Self {
+        StageConfig::new(name)
+    }
+}
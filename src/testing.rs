@@ -0,0 +1,295 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::future::{self, Future};
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+
+use crate::async_pipeline::{AsyncPipeline, AsyncPipelineHandle, AsyncStage, InputSlot};
+use crate::error::{StageError, StageReport};
+use crate::executor::{block_on, Executor, Task};
+use crate::link::Envelope;
+use crate::log::ItemId;
+use crate::stage::Stage;
+use crate::wiring::lock;
+
+/// An executor running its tasks on the calling thread, one at a time and in the order
+/// they were woken up, only when told to. Given the same inputs, a pipeline spawned on it
+/// always runs the same way, which makes it suited to testing stages.
+///
+/// Tasks woken up from other threads, e.g. by the timer behind `executor::sleep_until`,
+/// only run on the next call to `run_until_stalled` after that.
+#[derive(Default)]
+pub struct ManualExecutor {
+    queue: Arc<ManualQueue>,
+    /// Every task spawned so far, to drop the ones that are not done along with the executor.
+    tasks: Mutex<Vec<Arc<ManualTask>>>,
+}
+
+#[derive(Default)]
+struct ManualQueue {
+    ready: Mutex<VecDeque<Arc<ManualTask>>>,
+}
+
+struct ManualTask {
+    /// Gone once the task is done.
+    future: Mutex<Option<Task>>,
+    /// Whether the task is already waiting in the run queue, so it is queued only once.
+    scheduled: AtomicBool,
+    queue: Arc<ManualQueue>,
+}
+
+impl Wake for ManualTask {
+    fn wake(self: Arc<Self>) {
+        if !self.scheduled.swap(true, Ordering::SeqCst) {
+            lock(&self.queue.ready).push_back(self.clone());
+        }
+    }
+}
+
+impl ManualExecutor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Poll the tasks that were woken up, until none of them is. Returns how many polls it took.
+    /// A task that panics is dropped.
+    pub fn run_until_stalled(&self) -> usize {
+        let mut polls = 0;
+        loop {
+            // Not holding the lock while polling, so the task can wake itself or others.
+            let Some(task) = lock(&self.queue.ready).pop_front() else {
+                return polls;
+            };
+            polls += 1;
+            task.scheduled.store(false, Ordering::SeqCst);
+            let waker = Waker::from(task.clone());
+            let mut future = lock(&task.future);
+            let poll = future.as_mut().map(|future| {
+                let mut cx = Context::from_waker(&waker);
+                panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(&mut cx)))
+            });
+            if !matches!(poll, Some(Ok(Poll::Pending))) {
+                *future = None;
+            }
+        }
+    }
+
+    /// How many tasks are not done yet.
+    pub fn pending(&self) -> usize {
+        lock(&self.tasks)
+            .iter()
+            .filter(|task| lock(&task.future).is_some())
+            .count()
+    }
+}
+
+impl Executor for ManualExecutor {
+    fn spawn(&self, task: Task) {
+        let task = Arc::new(ManualTask {
+            future: Mutex::new(Some(task)),
+            scheduled: AtomicBool::new(false),
+            queue: self.queue.clone(),
+        });
+        lock(&self.tasks).push(task.clone());
+        task.wake();
+    }
+}
+
+impl Drop for ManualExecutor {
+    fn drop(&mut self) {
+        // Break the cycles between the tasks and the wakers they left behind.
+        lock(&self.queue.ready).clear();
+        for task in lock(&self.tasks).drain(..) {
+            lock(&task.future).take();
+        }
+    }
+}
+
+/// Runs a sync `Stage` as an async one, on the thread polling it, e.g. to step items
+/// through it with a `Harness`: `input.stage("square", Blocking(|num: u32| num * num))`.
+/// It holds up the executor's thread while it runs, like any blocking call.
+#[derive(Clone, Copy, Debug)]
+pub struct Blocking<S>(pub S);
+
+impl<In, Out, S> AsyncStage<In, Out> for Blocking<S>
+where
+    S: Stage<In, Out>,
+    In: Send,
+    Out: Send,
+{
+    fn process(&mut self, input: In) -> impl Future<Output = Out> + Send {
+        future::ready(self.0.process(input))
+    }
+}
+
+/// Runs an async pipeline on a `ManualExecutor`, feeding it items and collecting
+/// what comes out of it, e.g.
+///
+/// ```
+/// use rconcurrency_stuff::testing::Harness;
+///
+/// let mut harness = Harness::new(|input| input.stage("square", |num: u32| async move { num * num }));
+/// harness.push(2);
+/// harness.push(3);
+/// assert_eq!(harness.run(), [4, 9]);
+/// let finished = harness.finish()?;
+/// assert!(finished.errors.is_empty());
+/// # Ok::<(), rconcurrency_stuff::testing::Leak>(())
+/// ```
+///
+/// Items go through an unbounded link called `input` before reaching the first stage.
+///
+/// Only async pipelines run in a harness. Sync stages are wrapped in `Blocking` to be
+/// stepped through on the calling thread, but what only `Pipeline` has, e.g. `batch`,
+/// `window` or a pool of worker threads, is tested by running the pipeline for real.
+pub struct Harness<In, Out> {
+    executor: ManualExecutor,
+    input: InputSlot<In>,
+    next_id: u64,
+    handle: AsyncPipelineHandle<Out>,
+    /// Whether the output has been closed by the last stage.
+    closed: bool,
+}
+
+impl<In, Out> Harness<In, Out>
+where
+    In: Send + fmt::Debug + 'static,
+    Out: Send + fmt::Debug + 'static,
+{
+    /// Spawn the pipeline built by `build` on top of the harness' input.
+    /// Nothing runs until `run`, `finish` or `shutdown` is called.
+    pub fn new<F>(build: F) -> Self
+    where
+        F: FnOnce(AsyncPipeline<In>) -> AsyncPipeline<Out>,
+    {
+        let executor = ManualExecutor::new();
+        let input = InputSlot::default();
+        let handle = build(AsyncPipeline::input("input", input.clone())).spawn(&executor);
+        Harness {
+            executor,
+            input,
+            next_id: 0,
+            handle,
+            closed: false,
+        }
+    }
+
+    /// Feed an item to the first stage. Items are numbered in the order they are pushed.
+    /// Returns `false` if it was dropped, the input being closed or the pipeline cancelled.
+    pub fn push(&mut self, item: In) -> bool {
+        let id = ItemId(self.next_id);
+        self.next_id += 1;
+        let input = lock(&self.input).clone();
+        // The input link is unbounded, sending never waits.
+        input.is_some_and(|input| block_on(input.send(Envelope { id, item })).is_ok())
+    }
+
+    /// Signal the end of the input, as a source running out of items would.
+    pub fn close(&mut self) {
+        lock(&self.input).take();
+    }
+
+    /// Run the pipeline until every stage is waiting for more input,
+    /// returning the values that came out of it meanwhile, in order.
+    pub fn run(&mut self) -> Vec<Out> {
+        let mut outputs = Vec::new();
+        loop {
+            let polls = self.executor.run_until_stalled();
+            let received = self.drain(&mut outputs);
+            // Receiving makes room for the last stage, which may have more to send.
+            if polls == 0 && received == 0 {
+                return outputs;
+            }
+        }
+    }
+
+    /// The errors reported by the stages so far.
+    pub fn errors(&self) -> Vec<StageError> {
+        self.handle.errors().collect()
+    }
+
+    pub fn handle(&self) -> &AsyncPipelineHandle<Out> {
+        &self.handle
+    }
+
+    pub fn executor(&self) -> &ManualExecutor {
+        &self.executor
+    }
+
+    /// Close the input and run the pipeline to the end, checking that every stage
+    /// is done once it has nothing left to do.
+    pub fn finish(mut self) -> Result<Finished<Out>, Leak> {
+        self.close();
+        self.wind_down()
+    }
+
+    /// Cancel the pipeline and run it until it has stopped, checking that every stage
+    /// did stop. Values still in flight are dropped.
+    pub fn shutdown(mut self) -> Result<Finished<Out>, Leak> {
+        self.close();
+        self.handle.cancel();
+        self.wind_down()
+    }
+
+    fn wind_down(mut self) -> Result<Finished<Out>, Leak> {
+        let outputs = self.run();
+        let running = self.handle.running();
+        if !running.is_empty() {
+            return Err(Leak { stages: running });
+        }
+        let errors = self.errors();
+        let Harness {
+            executor, handle, ..
+        } = self;
+        // Every stage is done, so this does not wait.
+        let reports = block_on(handle.join());
+        drop(executor);
+        Ok(Finished {
+            outputs,
+            errors,
+            reports,
+        })
+    }
+
+    /// Receive the values that are ready without waiting for more, returning how many.
+    fn drain(&mut self, outputs: &mut Vec<Out>) -> usize {
+        let before = outputs.len();
+        let mut cx = Context::from_waker(Waker::noop());
+        while !self.closed {
+            match pin!(self.handle.recv()).poll(&mut cx) {
+                Poll::Ready(Some(out)) => outputs.push(out),
+                Poll::Ready(None) => self.closed = true,
+                Poll::Pending => break,
+            }
+        }
+        outputs.len() - before
+    }
+}
+
+/// How a pipeline run by a `Harness` ended.
+#[derive(Debug)]
+pub struct Finished<Out> {
+    /// The values that came out since the last call to `Harness::run`.
+    pub outputs: Vec<Out>,
+    pub errors: Vec<StageError>,
+    pub reports: Vec<StageReport>,
+}
+
+/// The stages still running once a pipeline should have stopped,
+/// e.g. waiting on a link that nobody will ever close.
+#[derive(Debug)]
+pub struct Leak {
+    pub stages: Vec<String>,
+}
+
+impl fmt::Display for Leak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stages still running: {}", self.stages.join(", "))
+    }
+}
+
+impl Error for Leak {}
@@ -0,0 +1,780 @@
+use std::any::Any;
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use crate::error::{PipelineError, TopologyError};
+use crate::event;
+use crate::link::{Envelope, Inbound, LinkConfig, Outbound};
+use crate::log::{self, Level, Logger, Silent};
+use crate::pipeline::{spawn_with, Pipeline, PipelineHandle};
+use crate::router::{spawn_router, Route, Router};
+use crate::sink::{spawn_sink, Sink};
+use crate::source::Source;
+use crate::stage::StageConfig;
+use crate::watchdog::Watchdog;
+use crate::wiring::Wiring;
+
+/// Whether an item takes a branch, see `Topology::connect_if`.
+type Filter<T> = Box<dyn Fn(&T) -> bool + Send>;
+
+/// A value whose type depends on the port it belongs to: a link end, a filter, etc.
+/// The typed handles of the ports make sure it is always the expected one.
+type Erased = Box<dyn Any + Send>;
+
+/// Spawns the stages of a node, once the links into it are wired.
+type Spawn = Box<dyn FnOnce(&Wiring, &mut Ports) -> Result<(), PipelineError> + Send>;
+
+/// Where items of type `T` go into a node of a `Topology`.
+pub struct Input<T> {
+    id: usize,
+    _marker: PhantomData<fn(T)>,
+}
+
+/// Where items of type `T` come out of a node of a `Topology`.
+pub struct Output<T> {
+    id: usize,
+    _marker: PhantomData<fn() -> T>,
+}
+
+// Not derived, which would require `T: Clone`.
+impl<T> Clone for Input<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Input<T> {}
+
+impl<T> Clone for Output<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Output<T> {}
+
+/// A node with a single input and a single output, see `Topology::node`.
+pub struct Node<In, Out> {
+    input: Input<In>,
+    output: Output<Out>,
+}
+
+impl<In, Out> Node<In, Out> {
+    pub fn input(&self) -> Input<In> {
+        self.input
+    }
+
+    pub fn output(&self) -> Output<Out> {
+        self.output
+    }
+}
+
+/// A node pairing the items of two inputs, see `Topology::zip`.
+pub struct Zip<A, B> {
+    left: Input<A>,
+    right: Input<B>,
+    output: Output<(A, B)>,
+}
+
+impl<A, B> Zip<A, B> {
+    pub fn left(&self) -> Input<A> {
+        self.left
+    }
+
+    pub fn right(&self) -> Input<B> {
+        self.right
+    }
+
+    pub fn output(&self) -> Output<(A, B)> {
+        self.output
+    }
+}
+
+/// A router node, see `Topology::router`.
+pub struct Routes<T> {
+    input: Input<T>,
+    branches: Vec<(String, Output<T>)>,
+    unmatched: Output<T>,
+}
+
+impl<T> Routes<T> {
+    pub fn input(&self) -> Input<T> {
+        self.input
+    }
+
+    /// Where the items routed to `branch` come out.
+    ///
+    /// # Panics
+    ///
+    /// If the rules of the router had no such branch when it was added to the topology.
+    pub fn branch(&self, branch: &str) -> Output<T> {
+        match self.branches.iter().find(|(name, _)| name == branch) {
+            Some((_, output)) => *output,
+            None => panic!("the router has no branch {:?}", branch),
+        }
+    }
+
+    /// Where the items no rule picks a branch for come out. If it is left unconnected,
+    /// they go to the router's dead letters instead.
+    pub fn unmatched(&self) -> Output<T> {
+        self.unmatched
+    }
+}
+
+struct NodeSpec {
+    name: String,
+    spawn: Spawn,
+}
+
+struct InputSpec {
+    /// `None` for the output of the whole topology.
+    node: Option<usize>,
+    /// The name of the port, and of the link into it if it needs one.
+    name: String,
+    link: LinkConfig,
+    /// Creates the link into the port, as an `(Outbound, Inbound)`.
+    make_link: fn(&Wiring, String, LinkConfig) -> (Erased, Erased),
+}
+
+struct OutputSpec {
+    node: usize,
+    name: String,
+    edges: Vec<Edge>,
+    /// Set by `Topology::broadcast`: what clones the items for each branch, as a `fn(&T) -> T`.
+    broadcast: Option<Erased>,
+    /// Whether the output can be left unconnected.
+    optional: bool,
+}
+
+struct Edge {
+    to: usize,
+    /// A `Filter<T>`, if the edge only takes some of the items.
+    filter: Option<Erased>,
+}
+
+/// A pipeline shaped as any directed acyclic graph of nodes, rather than as a single line:
+/// the items coming out of a node can be broadcast to several others or routed between them,
+/// and nodes can take in the items of several others, merged or zipped together.
+///
+/// Nodes are declared first, then their ports are connected. Connections are typed like the
+/// stages of a `Pipeline`, and the rest is checked by `spawn` before any stage is spawned:
+/// every port must be connected, no branch can be left unreachable, and there can't be cycles.
+///
+/// ```
+/// use rconcurrency_stuff::topology::Topology;
+/// use rconcurrency_stuff::Source;
+///
+/// let mut topology = Topology::new();
+/// let numbers = topology.source("generate", Source::new(1..=3u64));
+/// let square = topology.node("square", |input| input.stage("square", |num: u64| num * num));
+/// let cube = topology.node("cube", |input| input.stage("cube", |num: u64| num * num * num));
+/// let zip = topology.zip("zip");
+/// topology.broadcast(numbers);
+/// topology.connect(numbers, square.input());
+/// topology.connect(numbers, cube.input());
+/// topology.connect(square.output(), zip.left());
+/// topology.connect(cube.output(), zip.right());
+/// let pipeline = topology.spawn(zip.output())?;
+/// assert_eq!(pipeline.iter().collect::<Vec<_>>(), [(1, 1), (4, 8), (9, 27)]);
+/// # Ok::<(), rconcurrency_stuff::PipelineError>(())
+/// ```
+pub struct Topology {
+    nodes: Vec<NodeSpec>,
+    inputs: Vec<InputSpec>,
+    outputs: Vec<OutputSpec>,
+    logger: Arc<dyn Logger>,
+    watchdog: Option<Watchdog>,
+}
+
+impl Topology {
+    pub fn new() -> Self {
+        Topology {
+            nodes: Vec::new(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            logger: Arc::new(Silent),
+            watchdog: None,
+        }
+    }
+
+    /// Send the log events of every stage to `logger`, see `Pipeline::logger`.
+    pub fn logger(mut self, logger: impl Logger) -> Self {
+        self.logger = Arc::new(logger);
+        self
+    }
+
+    /// Keep an eye on the stages once the pipeline is cancelled, see `Pipeline::watchdog`.
+    pub fn watchdog(mut self, watchdog: Watchdog) -> Self {
+        self.watchdog = Some(watchdog);
+        self
+    }
+
+    /// A node emitting the items of `source`, named after its stage.
+    pub fn source<T>(&mut self, config: impl Into<StageConfig>, source: Source<T>) -> Output<T>
+    where
+        T: Send + Debug + 'static,
+    {
+        let config = config.into();
+        let name = config.name.clone();
+        let output = self.add_output(self.nodes.len(), name.clone());
+        self.add_node(name, move |wiring, ports| {
+            let outbound = Pipeline::source(config, source).wire(wiring)?;
+            ports.connect(wiring, output.id, outbound)
+        });
+        output
+    }
+
+    /// A node running the stages `build` appends to its input, e.g.
+    /// `|input| input.fan_out("square", FanOut::new(), square)`.
+    /// The link into the node is set up by `config`, and named after it. A node fed by a single
+    /// `connect`, with the default link settings, reads straight from the node before it instead.
+    pub fn node<In, Out, F>(&mut self, config: impl Into<StageConfig>, build: F) -> Node<In, Out>
+    where
+        In: Send + Debug + 'static,
+        Out: Send + Debug + 'static,
+        F: FnOnce(Pipeline<In>) -> Pipeline<Out> + Send + 'static,
+    {
+        let config = config.into();
+        let node = self.nodes.len();
+        let input = self.add_input::<In>(Some(node), config.name.clone(), config.link);
+        let output = self.add_output(node, config.name.clone());
+        self.add_node(config.name, move |wiring, ports| {
+            let inbound = ports.take_inbound(input.id);
+            let outbound = build(Pipeline::from_inbound(inbound)).wire(wiring)?;
+            ports.connect(wiring, output.id, outbound)
+        });
+        Node { input, output }
+    }
+
+    /// A node pairing the first item of `left` with the first item of `right`, and so on.
+    /// It stops as soon as either side does, dropping whatever is left on the other side.
+    /// Pairs go by the id of their left item.
+    pub fn zip<A, B>(&mut self, config: impl Into<StageConfig>) -> Zip<A, B>
+    where
+        A: Send + Debug + 'static,
+        B: Send + Debug + 'static,
+    {
+        let config = config.into();
+        let node = self.nodes.len();
+        let left = self.add_input::<A>(Some(node), format!("{}.left", config.name), config.link);
+        let right = self.add_input::<B>(Some(node), format!("{}.right", config.name), config.link);
+        let output = self.add_output(node, config.name.clone());
+        self.add_node(config.name.clone(), move |wiring, ports| {
+            let left = ports.take_inbound::<A>(left.id);
+            let right = ports.take_inbound::<B>(right.id);
+            let (tx, rx) = wiring.link(config.name.clone(), config.link);
+            spawn_zip(wiring, &config, left, right, tx)?;
+            ports.connect(wiring, output.id, rx)
+        });
+        Zip {
+            left,
+            right,
+            output,
+        }
+    }
+
+    /// A node sending each item to one of the branches of `router`, depending on what it holds.
+    /// The branches are those of its rules at this point, and are all outputs of the node,
+    /// named e.g. `parity.even` for the `even` branch of a router called `parity`.
+    pub fn router<T>(&mut self, config: impl Into<StageConfig>, router: Router<T>) -> Routes<T>
+    where
+        T: Send + Debug + 'static,
+    {
+        let config = config.into();
+        let node = self.nodes.len();
+        let input = self.add_input::<T>(Some(node), config.name.clone(), config.link);
+        let branches: Vec<_> = router
+            .branches()
+            .into_iter()
+            .map(|branch| {
+                let output = self.add_output(node, format!("{}.{}", config.name, branch));
+                (branch, output)
+            })
+            .collect();
+        let unmatched = self.add_output(node, format!("{}.unmatched", config.name));
+        self.outputs[unmatched.id].optional = true;
+        let routes = Routes {
+            input,
+            branches: branches.clone(),
+            unmatched,
+        };
+        self.add_node(config.name.clone(), move |wiring, ports| {
+            let inbound = ports.take_inbound(input.id);
+            let mut routes = Vec::with_capacity(branches.len());
+            for (name, output) in branches {
+                let (tx, rx) = wiring.link(format!("{}.{}", config.name, name), config.link);
+                ports.connect(wiring, output.id, rx)?;
+                routes.push(Route {
+                    name,
+                    outbound: Some(tx),
+                });
+            }
+            let unmatched = match ports.is_connected(unmatched.id) {
+                true => {
+                    let name = format!("{}.unmatched", config.name);
+                    let (tx, rx) = wiring.link(name, config.link);
+                    ports.connect(wiring, unmatched.id, rx)?;
+                    Some(tx)
+                }
+                false => None,
+            };
+            spawn_router(wiring, &config, router, inbound, routes, unmatched)
+        });
+        routes
+    }
+
+    /// A node consuming every item coming into it with `sink`, ending a branch,
+    /// e.g. `ForEach::new(|item| audit(item))`. See `run` for the sink of the whole topology.
+    pub fn sink<T, S>(&mut self, config: impl Into<StageConfig>, sink: S) -> Input<T>
+    where
+        T: Send + Debug + 'static,
+        S: Sink<T, Output = ()>,
+    {
+        let config = config.into();
+        let node = self.nodes.len();
+        let input = self.add_input::<T>(Some(node), config.name.clone(), config.link);
+        // `config` sets up the link into the sink, not the one out of it.
+        let stage = StageConfig {
+            link: LinkConfig::default(),
+            ..config.clone()
+        };
+        self.add_node(config.name, move |wiring, ports| {
+            let inbound = ports.take_inbound(input.id);
+            // There is nothing to wait for once the sink is done.
+            let (tx, _) = wiring.link(format!("{}.done", stage.name), stage.link);
+            spawn_sink(wiring, &stage, sink, inbound, tx)
+        });
+        input
+    }
+
+    /// Send the items coming out of `from` into `to`. An input connected to several outputs
+    /// takes in the items of all of them, in the order they come.
+    ///
+    /// An output connected to several inputs sends each item to the first of them, in the order
+    /// they were connected in, that accepts it (see `connect_if`), unless it is a `broadcast`.
+    pub fn connect<T>(&mut self, from: Output<T>, to: Input<T>) {
+        self.outputs[from.id].edges.push(Edge {
+            to: to.id,
+            filter: None,
+        });
+    }
+
+    /// Same as `connect`, only for the items for which `filter` returns `true`.
+    /// Items no branch accepts are dropped.
+    pub fn connect_if<T, F>(&mut self, from: Output<T>, to: Input<T>, filter: F)
+    where
+        T: 'static,
+        F: Fn(&T) -> bool + Send + 'static,
+    {
+        let filter: Filter<T> = Box::new(filter);
+        self.outputs[from.id].edges.push(Edge {
+            to: to.id,
+            filter: Some(Box::new(filter)),
+        });
+    }
+
+    /// Send a copy of every item coming out of `from` to each of the inputs it is connected to
+    /// (that accepts it, see `connect_if`), instead of only to the first one.
+    ///
+    /// Each subscriber buffers its copies in the link into it, set up by the `StageConfig` of
+    /// its node, whose backpressure decides what happens when the subscriber can't keep up:
+    /// `Block` holds up every subscriber until it catches up, `DropNewest` and `DropOldest`
+    /// make it miss items, and `Disconnect` cuts it off while the others go on, e.g.
+    /// `StageConfig::new("audit").capacity(64).backpressure(Backpressure::Disconnect)`.
+    pub fn broadcast<T>(&mut self, from: Output<T>)
+    where
+        T: Clone + 'static,
+    {
+        let clone: fn(&T) -> T = T::clone;
+        self.outputs[from.id].broadcast = Some(Box::new(clone));
+    }
+
+    /// Check the topology, then spawn every stage and return the handle to the running
+    /// pipeline, whose output is the items coming out of `output`.
+    /// Nothing is spawned if the topology is invalid, see `TopologyError`.
+    pub fn spawn<T>(mut self, output: Output<T>) -> Result<PipelineHandle<T>, PipelineError>
+    where
+        T: Send + Debug + 'static,
+    {
+        let handle = self.add_input::<T>(None, "output".to_owned(), LinkConfig::default());
+        self.connect(output, handle);
+        let order = self.validate().map_err(PipelineError::Topology)?;
+        let Topology {
+            nodes,
+            inputs,
+            outputs,
+            logger,
+            watchdog,
+        } = self;
+        spawn_with(logger, watchdog, move |wiring| {
+            let mut ports = Ports::new(wiring, inputs, outputs);
+            let mut nodes: Vec<_> = nodes.into_iter().map(Some).collect();
+            for node in order {
+                if let Some(node) = nodes[node].take() {
+                    (node.spawn)(wiring, &mut ports)?;
+                }
+            }
+            // Dropping the other ports closes the links into them once their senders are done.
+            Ok(ports.take_inbound(handle.id))
+        })
+    }
+
+    /// Run the topology to completion, with the items coming out of `output` going into
+    /// `sink`, and return its output once every stage has exited. The sink node is called `sink`,
+    /// see `Pipeline::run`.
+    pub fn run<T, S>(mut self, output: Output<T>, sink: S) -> Result<S::Output, PipelineError>
+    where
+        T: Send + Debug + 'static,
+        S: Sink<T>,
+    {
+        let sink = self.node("sink", |input| input.sink("sink", sink));
+        self.connect(output, sink.input());
+        let pipeline = self.spawn(sink.output())?;
+        let output = pipeline.recv();
+        for report in pipeline.join() {
+            report.result?;
+        }
+        output.ok_or(PipelineError::Cancelled)
+    }
+
+    fn add_node<F>(&mut self, name: String, spawn: F)
+    where
+        F: FnOnce(&Wiring, &mut Ports) -> Result<(), PipelineError> + Send + 'static,
+    {
+        self.nodes.push(NodeSpec {
+            name,
+            spawn: Box::new(spawn),
+        });
+    }
+
+    fn add_output<T>(&mut self, node: usize, name: String) -> Output<T> {
+        self.outputs.push(OutputSpec {
+            node,
+            name,
+            edges: Vec::new(),
+            broadcast: None,
+            optional: false,
+        });
+        Output {
+            id: self.outputs.len() - 1,
+            _marker: PhantomData,
+        }
+    }
+
+    fn add_input<T: Send + 'static>(
+        &mut self,
+        node: Option<usize>,
+        name: String,
+        link: LinkConfig,
+    ) -> Input<T> {
+        self.inputs.push(InputSpec {
+            node,
+            name,
+            link,
+            make_link: make_link::<T>,
+        });
+        Input {
+            id: self.inputs.len() - 1,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Check that the topology can be spawned, returning the order to spawn its nodes in:
+    /// every node comes after the ones it takes items from.
+    fn validate(&self) -> Result<Vec<usize>, TopologyError> {
+        let mut names = HashSet::new();
+        for node in &self.nodes {
+            if !names.insert(&node.name) {
+                return Err(TopologyError::DuplicateNode(node.name.clone()));
+            }
+        }
+        let mut connected = vec![false; self.inputs.len()];
+        for output in &self.outputs {
+            if output.edges.is_empty() && !output.optional {
+                return Err(TopologyError::UnconnectedOutput(output.name.clone()));
+            }
+            let mut takes_all = false;
+            for edge in &output.edges {
+                if takes_all && output.broadcast.is_none() {
+                    return Err(TopologyError::Unreachable {
+                        from: output.name.clone(),
+                        to: self.inputs[edge.to].name.clone(),
+                    });
+                }
+                takes_all |= edge.filter.is_none();
+                connected[edge.to] = true;
+            }
+        }
+        if let Some(input) = connected.iter().position(|&connected| !connected) {
+            return Err(TopologyError::UnconnectedInput(
+                self.inputs[input].name.clone(),
+            ));
+        }
+        // Depth first, the nodes being done in the reverse order they should be spawned in.
+        let mut marks = vec![Mark::Unvisited; self.nodes.len()];
+        let mut order = Vec::with_capacity(self.nodes.len());
+        for node in 0..self.nodes.len() {
+            self.visit(node, &mut marks, &mut order, &mut Vec::new())?;
+        }
+        order.reverse();
+        Ok(order)
+    }
+
+    fn visit(
+        &self,
+        node: usize,
+        marks: &mut [Mark],
+        order: &mut Vec<usize>,
+        path: &mut Vec<usize>,
+    ) -> Result<(), TopologyError> {
+        match marks[node] {
+            Mark::Done => return Ok(()),
+            Mark::Visiting => {
+                let start = path.iter().position(|&visiting| visiting == node);
+                let cycle = path[start.unwrap_or(0)..]
+                    .iter()
+                    .chain([&node])
+                    .map(|&node| self.nodes[node].name.clone())
+                    .collect();
+                return Err(TopologyError::Cycle(cycle));
+            }
+            Mark::Unvisited => {}
+        }
+        marks[node] = Mark::Visiting;
+        path.push(node);
+        let next = self
+            .outputs
+            .iter()
+            .filter(|output| output.node == node)
+            .flat_map(|output| &output.edges)
+            .filter_map(|edge| self.inputs[edge.to].node);
+        for next in next {
+            self.visit(next, marks, order, path)?;
+        }
+        path.pop();
+        marks[node] = Mark::Done;
+        order.push(node);
+        Ok(())
+    }
+}
+
+impl Default for Topology {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Mark {
+    Unvisited,
+    Visiting,
+    Done,
+}
+
+fn make_link<T: Send + 'static>(
+    wiring: &Wiring,
+    name: String,
+    link: LinkConfig,
+) -> (Erased, Erased) {
+    let (tx, rx) = wiring.link::<Envelope<T>>(name, link);
+    (Box::new(tx), Box::new(rx))
+}
+
+/// The links of a topology being spawned, by port.
+struct Ports {
+    /// The link out of each input, taken by the node it belongs to.
+    inbounds: Vec<Option<Erased>>,
+    /// The link into each input, unless it is fed directly by the only output connected to it.
+    outbounds: Vec<Option<Erased>>,
+    /// Taken once the node they belong to is spawned.
+    outputs: Vec<Option<OutputSpec>>,
+}
+
+impl Ports {
+    fn new(wiring: &Wiring, inputs: Vec<InputSpec>, outputs: Vec<OutputSpec>) -> Self {
+        let mut edges_into = vec![0; inputs.len()];
+        for edge in outputs.iter().flat_map(|output| &output.edges) {
+            edges_into[edge.to] += 1;
+        }
+        let mut inbounds = Vec::with_capacity(inputs.len());
+        let mut outbounds = Vec::with_capacity(inputs.len());
+        for (input, edges_into) in inputs.into_iter().zip(edges_into) {
+            // An input set up by its node needs a link of its own, as configured.
+            let direct = edges_into == 1
+                && input.link == LinkConfig::default()
+                && outputs
+                    .iter()
+                    .any(|output| is_direct(output) && output.edges[0].to == inbounds.len());
+            if direct {
+                inbounds.push(None);
+                outbounds.push(None);
+            } else {
+                let (tx, rx) = (input.make_link)(wiring, input.name, input.link);
+                inbounds.push(Some(rx));
+                outbounds.push(Some(tx));
+            }
+        }
+        Ports {
+            inbounds,
+            outbounds,
+            outputs: outputs.into_iter().map(Some).collect(),
+        }
+    }
+
+    fn is_connected(&self, output: usize) -> bool {
+        self.outputs[output]
+            .as_ref()
+            .is_some_and(|output| !output.edges.is_empty())
+    }
+
+    fn take_inbound<T: 'static>(&mut self, input: usize) -> Inbound<Envelope<T>> {
+        let inbound = self.inbounds[input]
+            .take()
+            .expect("the nodes are spawned after the ones feeding them");
+        *inbound
+            .downcast()
+            .expect("ports are typed, and come from the same topology")
+    }
+
+    /// Send the items of `inbound`, coming out of `output`, where it is connected to.
+    fn connect<T>(
+        &mut self,
+        wiring: &Wiring,
+        output: usize,
+        inbound: Inbound<Envelope<T>>,
+    ) -> Result<(), PipelineError>
+    where
+        T: Send + Debug + 'static,
+    {
+        let output = self.outputs[output]
+            .take()
+            .expect("every output belongs to a single node");
+        if is_direct(&output) && self.outbounds[output.edges[0].to].is_none() {
+            self.inbounds[output.edges[0].to] = Some(Box::new(inbound));
+            return Ok(());
+        }
+        let branches = output
+            .edges
+            .into_iter()
+            .map(|Edge { to, filter }| Branch {
+                filter: filter.map(|filter| {
+                    *filter
+                        .downcast::<Filter<T>>()
+                        .expect("filters are typed like their output")
+                }),
+                outbound: self.outbounds[to]
+                    .as_ref()
+                    .and_then(|outbound| outbound.downcast_ref::<Outbound<Envelope<T>>>())
+                    .cloned(),
+            })
+            .collect();
+        let clone = output.broadcast.map(|clone| {
+            *clone
+                .downcast::<fn(&T) -> T>()
+                .expect("broadcasts are typed like their output")
+        });
+        let config = StageConfig::new(&format!("{}-split", output.name));
+        spawn_split(wiring, &config, inbound, branches, clone)
+    }
+}
+
+/// Whether the only edge out of `output` takes every item, so it needs no thread of its own.
+fn is_direct(output: &OutputSpec) -> bool {
+    matches!(&output.edges[..], [Edge { filter: None, .. }]) && output.broadcast.is_none()
+}
+
+/// One of the inputs an output is connected to.
+struct Branch<T> {
+    filter: Option<Filter<T>>,
+    /// Gone once nobody is listening anymore.
+    outbound: Option<Outbound<Envelope<T>>>,
+}
+
+impl<T> Branch<T> {
+    fn accepts(&self, item: &T) -> bool {
+        self.filter.as_ref().is_none_or(|filter| filter(item))
+    }
+}
+
+/// Send the items of `inbound` to its branches, to the first one accepting each of them,
+/// or to all of them if they are cloned for each.
+fn spawn_split<T>(
+    wiring: &Wiring,
+    config: &StageConfig,
+    inbound: Inbound<Envelope<T>>,
+    mut branches: Vec<Branch<T>>,
+    clone: Option<fn(&T) -> T>,
+) -> Result<(), PipelineError>
+where
+    T: Send + Debug + 'static,
+{
+    wiring.spawn(config, move |ctx| {
+        while let Some(Envelope { id, item }) = inbound.recv() {
+            log::enter_item(Some(id));
+            match clone {
+                Some(clone) => {
+                    for branch in &mut branches {
+                        if !branch.accepts(&item) {
+                            continue;
+                        }
+                        let item = clone(&item);
+                        if let Some(outbound) = &branch.outbound {
+                            if !ctx.forward(outbound, Envelope { id, item }) {
+                                branch.outbound = None;
+                            }
+                        }
+                    }
+                }
+                None => match branches.iter_mut().find(|branch| branch.accepts(&item)) {
+                    // The items for a branch that is gone are lost, rather than
+                    // going to another branch they were not meant for.
+                    Some(branch) => {
+                        if let Some(outbound) = &branch.outbound {
+                            if !ctx.forward(outbound, Envelope { id, item }) {
+                                branch.outbound = None;
+                            }
+                        }
+                    }
+                    None => event!(Level::Debug, "no branch for {:?}", item),
+                },
+            }
+            if branches.iter().all(|branch| branch.outbound.is_none()) {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
+
+fn spawn_zip<A, B>(
+    wiring: &Wiring,
+    config: &StageConfig,
+    left: Inbound<Envelope<A>>,
+    right: Inbound<Envelope<B>>,
+    outbound: Outbound<Envelope<(A, B)>>,
+) -> Result<(), PipelineError>
+where
+    A: Send + Debug + 'static,
+    B: Send + Debug + 'static,
+{
+    wiring.spawn(config, move |ctx| {
+        while let Some(Envelope { id, item: left }) = left.recv() {
+            log::enter_item(Some(id));
+            let Some(Envelope { item: right, .. }) = right.recv() else {
+                break;
+            };
+            if !ctx.forward(
+                &outbound,
+                Envelope {
+                    id,
+                    item: (left, right),
+                },
+            ) {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
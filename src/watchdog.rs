@@ -0,0 +1,268 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::cancel::CancellationToken;
+use crate::event;
+use crate::log::{self, ItemId, Level, Logger};
+use crate::wiring::lock;
+
+/// How often the watchdog checks on the stages.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What a stage is doing, as far as the watchdog can tell.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Activity {
+    /// Running the stage's own code.
+    Running,
+    /// Waiting for an item from upstream.
+    Receiving {
+        link: String,
+    },
+    /// Waiting for room downstream.
+    Sending {
+        link: String,
+    },
+    Stopped,
+}
+
+impl fmt::Display for Activity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Activity::Running => f.write_str("running"),
+            Activity::Receiving { link } => write!(f, "blocked receiving from {:?}", link),
+            Activity::Sending { link } => write!(f, "blocked sending to {:?}", link),
+            Activity::Stopped => f.write_str("stopped"),
+        }
+    }
+}
+
+/// A snapshot of what a stage is doing, see `PipelineHandle::status`.
+#[derive(Clone, Debug)]
+pub struct StageStatus {
+    pub stage: String,
+    pub activity: Activity,
+    /// The last item the stage started on, if any.
+    pub item: Option<ItemId>,
+    /// How long the stage has been at it.
+    pub since: Duration,
+}
+
+impl fmt::Display for StageStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} for {:.2?}",
+            self.stage, self.activity, self.since
+        )?;
+        if let Some(item) = self.item {
+            write!(f, " (last item {})", item)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where a stage reports what it is doing, for the watchdog to check on.
+pub(crate) struct Probe {
+    stage: String,
+    state: Mutex<ProbeState>,
+}
+
+struct ProbeState {
+    activity: Activity,
+    item: Option<ItemId>,
+    since: Instant,
+}
+
+impl Probe {
+    pub(crate) fn new(stage: String) -> Self {
+        Probe {
+            stage,
+            state: Mutex::new(ProbeState {
+                activity: Activity::Running,
+                item: None,
+                since: Instant::now(),
+            }),
+        }
+    }
+
+    pub(crate) fn set(&self, activity: Activity) {
+        let mut state = lock(&self.state);
+        if state.activity != activity {
+            state.activity = activity;
+            state.since = Instant::now();
+        }
+    }
+
+    pub(crate) fn status(&self) -> StageStatus {
+        let state = lock(&self.state);
+        StageStatus {
+            stage: self.stage.clone(),
+            activity: state.activity.clone(),
+            item: state.item,
+            since: state.since.elapsed(),
+        }
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        lock(&self.state).activity == Activity::Stopped
+    }
+}
+
+thread_local! {
+    static PROBE: RefCell<Option<Arc<Probe>>> = const { RefCell::new(None) };
+}
+
+/// Report what this thread does to `probe`, until the next call.
+pub(crate) fn attach(probe: Option<Arc<Probe>>) {
+    PROBE.with(|current| *current.borrow_mut() = probe);
+}
+
+/// Report what the stage running on this thread is doing, if any.
+pub(crate) fn set_activity(activity: Activity) {
+    PROBE.with(|probe| {
+        if let Some(probe) = probe.borrow().as_ref() {
+            probe.set(activity);
+        }
+    });
+}
+
+/// Report the item the stage running on this thread is starting on, if any.
+pub(crate) fn set_item(item: Option<ItemId>) {
+    PROBE.with(|probe| {
+        if let Some(probe) = probe.borrow().as_ref() {
+            lock(&probe.state).item = item;
+        }
+    });
+}
+
+/// The status of the stages of `probes` that have not stopped yet.
+pub(crate) fn running(probes: &Mutex<Vec<Arc<Probe>>>) -> Vec<StageStatus> {
+    lock(probes)
+        .iter()
+        .filter(|probe| !probe.is_stopped())
+        .map(|probe| probe.status())
+        .collect()
+}
+
+type OnStuck = Arc<dyn Fn(&Stuck) + Send + Sync>;
+
+/// Keeps an eye on the stages of a pipeline once it is shut down, see `Pipeline::watchdog`.
+/// The stages still running `timeout` after the pipeline was cancelled are logged as an error,
+/// along with what each of them is blocked on.
+#[derive(Clone)]
+pub struct Watchdog {
+    timeout: Duration,
+    on_stuck: Option<OnStuck>,
+}
+
+impl Watchdog {
+    pub fn new(timeout: Duration) -> Self {
+        Watchdog {
+            timeout,
+            on_stuck: None,
+        }
+    }
+
+    /// Also hand the stuck stages to `on_stuck`, e.g. to fail a test or dump them elsewhere.
+    pub fn on_stuck(mut self, on_stuck: impl Fn(&Stuck) + Send + Sync + 'static) -> Self {
+        self.on_stuck = Some(Arc::new(on_stuck));
+        self
+    }
+}
+
+impl fmt::Debug for Watchdog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watchdog")
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The stages that did not stop in time once their pipeline was shut down.
+/// Displays as a diagnostic dump, one line per stage.
+#[derive(Clone, Debug)]
+pub struct Stuck {
+    /// How long the stages were given to stop.
+    pub waited: Duration,
+    pub stages: Vec<StageStatus>,
+}
+
+impl fmt::Display for Stuck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} stage(s) still running {:.2?} after shutdown:",
+            self.stages.len(),
+            self.waited
+        )?;
+        for stage in &self.stages {
+            write!(f, "\n  {}", stage)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Stuck {}
+
+/// The thread of a pipeline's watchdog, which stops once this is dropped.
+pub(crate) struct WatchdogThread {
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl WatchdogThread {
+    /// Stop the watchdog, without reporting anything more, and wait for its thread to exit.
+    pub(crate) fn join(self) {
+        drop(self.stop);
+        // Reporting stuck stages may have panicked, in `on_stuck`: that's on the caller.
+        let _ = self.thread.join();
+    }
+}
+
+/// Run `watchdog` on its own thread, until every stage has stopped,
+/// it has reported the ones that are stuck, or it is stopped.
+pub(crate) fn spawn_watchdog(
+    watchdog: Watchdog,
+    token: CancellationToken,
+    probes: Arc<Mutex<Vec<Arc<Probe>>>>,
+    logger: Arc<dyn Logger>,
+) -> io::Result<WatchdogThread> {
+    let (stop, stopped) = channel::<()>();
+    let thread = thread::Builder::new()
+        .name("watchdog".to_owned())
+        .spawn(move || {
+            log::enter_stage("watchdog", logger);
+            let mut cancelled_at = None;
+            loop {
+                let running = running(&probes);
+                if running.is_empty() {
+                    return;
+                }
+                if token.is_cancelled() {
+                    let cancelled_at = *cancelled_at.get_or_insert_with(Instant::now);
+                    if cancelled_at.elapsed() >= watchdog.timeout {
+                        let stuck = Stuck {
+                            waited: watchdog.timeout,
+                            stages: running,
+                        };
+                        event!(Level::Error, "{}", stuck);
+                        if let Some(on_stuck) = &watchdog.on_stuck {
+                            on_stuck(&stuck);
+                        }
+                        return;
+                    }
+                }
+                // Nothing is ever sent: the sender being dropped is what stops the watchdog.
+                if let Err(RecvTimeoutError::Disconnected) = stopped.recv_timeout(POLL_INTERVAL) {
+                    return;
+                }
+            }
+        })?;
+    Ok(WatchdogThread { stop, thread })
+}
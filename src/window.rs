@@ -0,0 +1,539 @@
+use std::any::type_name;
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug, Display};
+use std::ops::AddAssign;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::error::{PipelineError, StageErrorKind};
+use crate::event;
+use crate::link::{Envelope, Inbound, Outbound};
+use crate::log::{self, ItemId, Level};
+use crate::numeric::Integer;
+use crate::stage::StageConfig;
+use crate::wiring::{StageCtx, Wiring};
+
+/// Gives the time an item happened at, see `Window::event_time`.
+type Timestamp<T> = Box<dyn Fn(&T) -> Duration + Send>;
+
+#[derive(Clone, Copy, Debug)]
+enum Shape {
+    Tumbling { size: Duration },
+    Sliding { size: Duration, slide: Duration },
+    Session { gap: Duration },
+}
+
+/// How a windowing stage groups items over time, see `Pipeline::window`.
+///
+/// Times are durations since the Unix epoch: by default, the wall clock time at which
+/// the items arrive, and windows close as the clock goes by.
+/// With `event_time`, windows close as the times of the items go by instead,
+/// which is called the watermark.
+pub struct Window<T> {
+    shape: Shape,
+    event_time: Option<Timestamp<T>>,
+    allowed_lateness: Duration,
+}
+
+impl<T> Window<T> {
+    /// Windows of `size`, one right after the other: every item falls in exactly one of them.
+    pub fn tumbling(size: Duration) -> Self {
+        assert!(!size.is_zero(), "a window can't be empty");
+        Self::new(Shape::Tumbling { size })
+    }
+
+    /// Windows of `size` starting every `slide`, so that they overlap:
+    /// every item falls in `size / slide` of them.
+    pub fn sliding(size: Duration, slide: Duration) -> Self {
+        assert!(
+            !slide.is_zero() && slide <= size,
+            "sliding windows must move forward without leaving gaps between them"
+        );
+        Self::new(Shape::Sliding { size, slide })
+    }
+
+    /// Windows of activity, which keep growing as long as items come less than `gap` apart.
+    pub fn session(gap: Duration) -> Self {
+        assert!(!gap.is_zero(), "a window can't be empty");
+        Self::new(Shape::Session { gap })
+    }
+
+    /// Go by the time the items say they happened at, e.g. a timestamp they carry,
+    /// rather than by the time they arrive at.
+    pub fn event_time(mut self, time: impl Fn(&T) -> Duration + Send + 'static) -> Self {
+        self.event_time = Some(Box::new(time));
+        self
+    }
+
+    /// With `event_time`, keep the windows open `allowed_lateness` past their end,
+    /// for the items that come out of order. Nothing is ever late by the wall clock.
+    pub fn allowed_lateness(mut self, allowed_lateness: Duration) -> Self {
+        self.allowed_lateness = allowed_lateness;
+        self
+    }
+
+    fn new(shape: Shape) -> Self {
+        Window {
+            shape,
+            event_time: None,
+            allowed_lateness: Duration::ZERO,
+        }
+    }
+}
+
+/// The aggregate of the items of a window, along with when the window started and ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Windowed<A> {
+    pub start: Duration,
+    pub end: Duration,
+    pub value: A,
+}
+
+/// What a windowing stage computes over the items of each window, see `Pipeline::window`.
+/// Items may fall in several windows at once, so they are only borrowed.
+///
+/// Aggregates can be combined as tuples, e.g. `(Count, Sum)`.
+pub trait Aggregate<T>: Send + 'static {
+    /// What is kept of a window while it is open.
+    type Acc: Send;
+    type Output: Send + Debug + 'static;
+
+    /// Open a window with its first item.
+    fn start(&self, item: &T) -> Self::Acc;
+
+    fn add(&self, acc: &mut Self::Acc, item: &T);
+
+    /// Combine `other` into `acc`, for two session windows that an item brought together.
+    fn merge(&self, acc: &mut Self::Acc, other: Self::Acc);
+
+    fn finish(&self, acc: Self::Acc) -> Self::Output;
+}
+
+/// How many items fell in the window.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Count;
+
+impl<T> Aggregate<T> for Count {
+    type Acc = u64;
+    type Output = u64;
+
+    fn start(&self, _: &T) -> u64 {
+        1
+    }
+
+    fn add(&self, acc: &mut u64, _: &T) {
+        *acc += 1;
+    }
+
+    fn merge(&self, acc: &mut u64, other: u64) {
+        *acc += other;
+    }
+
+    fn finish(&self, acc: u64) -> u64 {
+        acc
+    }
+}
+
+/// The sum of the items of the window. It overflows like `+=` does: panicking in debug builds
+/// and wrapping around in release ones. For integers that may add up to too much,
+/// see `CheckedSum`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sum;
+
+impl<T> Aggregate<T> for Sum
+where
+    T: Clone + AddAssign + Send + Debug + 'static,
+{
+    type Acc = T;
+    type Output = T;
+
+    fn start(&self, item: &T) -> T {
+        item.clone()
+    }
+
+    fn add(&self, acc: &mut T, item: &T) {
+        *acc += item.clone();
+    }
+
+    fn merge(&self, acc: &mut T, other: T) {
+        *acc += other;
+    }
+
+    fn finish(&self, acc: T) -> T {
+        acc
+    }
+}
+
+/// The sum of the integers of the window, or an error once it no longer fits in their type,
+/// the same in debug and release builds. A `try_stage` right after reports those windows.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CheckedSum;
+
+impl<N: Integer> Aggregate<N> for CheckedSum {
+    /// How many items went in, and their sum until it overflowed.
+    type Acc = (u64, Option<N>);
+    type Output = Result<N, SumOverflow>;
+
+    fn start(&self, item: &N) -> (u64, Option<N>) {
+        (1, Some(item.clone()))
+    }
+
+    fn add(&self, (count, sum): &mut (u64, Option<N>), item: &N) {
+        *count += 1;
+        *sum = sum.take().and_then(|sum| sum.checked_add(item));
+    }
+
+    fn merge(&self, (count, sum): &mut (u64, Option<N>), (other_count, other): (u64, Option<N>)) {
+        *count += other_count;
+        *sum = sum
+            .take()
+            .zip(other)
+            .and_then(|(sum, other)| sum.checked_add(&other));
+    }
+
+    fn finish(&self, (count, sum): (u64, Option<N>)) -> Result<N, SumOverflow> {
+        sum.ok_or(SumOverflow {
+            count,
+            type_name: type_name::<N>().rsplit("::").next().unwrap_or_default(),
+        })
+    }
+}
+
+/// A window whose items add up to more than their type holds, see `CheckedSum`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SumOverflow {
+    /// How many items fell in the window.
+    pub count: u64,
+    type_name: &'static str,
+}
+
+impl Display for SumOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the sum of {} items does not fit in a {}",
+            self.count, self.type_name
+        )
+    }
+}
+
+impl std::error::Error for SumOverflow {}
+
+/// The smallest item of the window.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Min;
+
+impl<T> Aggregate<T> for Min
+where
+    T: Clone + Ord + Send + Debug + 'static,
+{
+    type Acc = T;
+    type Output = T;
+
+    fn start(&self, item: &T) -> T {
+        item.clone()
+    }
+
+    fn add(&self, acc: &mut T, item: &T) {
+        if *item < *acc {
+            *acc = item.clone();
+        }
+    }
+
+    fn merge(&self, acc: &mut T, other: T) {
+        if other < *acc {
+            *acc = other;
+        }
+    }
+
+    fn finish(&self, acc: T) -> T {
+        acc
+    }
+}
+
+/// The largest item of the window.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Max;
+
+impl<T> Aggregate<T> for Max
+where
+    T: Clone + Ord + Send + Debug + 'static,
+{
+    type Acc = T;
+    type Output = T;
+
+    fn start(&self, item: &T) -> T {
+        item.clone()
+    }
+
+    fn add(&self, acc: &mut T, item: &T) {
+        if *item > *acc {
+            *acc = item.clone();
+        }
+    }
+
+    fn merge(&self, acc: &mut T, other: T) {
+        if other > *acc {
+            *acc = other;
+        }
+    }
+
+    fn finish(&self, acc: T) -> T {
+        acc
+    }
+}
+
+impl<T, A, B> Aggregate<T> for (A, B)
+where
+    A: Aggregate<T>,
+    B: Aggregate<T>,
+{
+    type Acc = (A::Acc, B::Acc);
+    type Output = (A::Output, B::Output);
+
+    fn start(&self, item: &T) -> Self::Acc {
+        (self.0.start(item), self.1.start(item))
+    }
+
+    fn add(&self, acc: &mut Self::Acc, item: &T) {
+        self.0.add(&mut acc.0, item);
+        self.1.add(&mut acc.1, item);
+    }
+
+    fn merge(&self, acc: &mut Self::Acc, other: Self::Acc) {
+        self.0.merge(&mut acc.0, other.0);
+        self.1.merge(&mut acc.1, other.1);
+    }
+
+    fn finish(&self, acc: Self::Acc) -> Self::Output {
+        (self.0.finish(acc.0), self.1.finish(acc.1))
+    }
+}
+
+/// A window that is still taking items in.
+struct Open<Acc> {
+    end: Duration,
+    /// The id of the first item, which the window goes by downstream.
+    id: ItemId,
+    acc: Acc,
+}
+
+/// The wall clock, as a duration since the Unix epoch that never goes backwards.
+struct Clock {
+    started: Instant,
+    epoch: Duration,
+}
+
+impl Clock {
+    fn new() -> Self {
+        Clock {
+            started: Instant::now(),
+            epoch: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+        }
+    }
+
+    fn now(&self) -> Duration {
+        self.epoch + self.started.elapsed()
+    }
+
+    /// When the clock reads `time`.
+    fn instant(&self, time: Duration) -> Instant {
+        self.started + time.saturating_sub(self.epoch)
+    }
+}
+
+/// `time` rounded down to a multiple of `step`.
+fn align(time: Duration, step: Duration) -> Duration {
+    // The remainder is less than `step`, which fits in a `u64` of nanoseconds.
+    time - Duration::from_nanos((time.as_nanos() % step.as_nanos()) as u64)
+}
+
+pub(crate) fn spawn_windower<T, A>(
+    wiring: &Wiring,
+    config: &StageConfig,
+    window: Window<T>,
+    aggregate: A,
+    inbound: Inbound<Envelope<T>>,
+    outbound: Outbound<Envelope<Windowed<A::Output>>>,
+) -> Result<(), PipelineError>
+where
+    T: Send + Debug + 'static,
+    A: Aggregate<T>,
+{
+    wiring.spawn(config, move |ctx| {
+        let clock = Clock::new();
+        // Keyed by start. Windows of the same stage never end in a different order
+        // than they start in, so the first one is always the next one to close.
+        let mut open = BTreeMap::new();
+        // Windows ending at or before the watermark are closed, and take no more items.
+        let mut watermark = Duration::ZERO;
+        loop {
+            // By the wall clock, windows close as time goes by, whether items come or not.
+            let deadline = match window.event_time {
+                Some(_) => None,
+                None => open
+                    .first_key_value()
+                    .map(|(_, first): (_, &Open<A::Acc>)| clock.instant(first.end)),
+            };
+            let Envelope { id, item } = match inbound.recv_deadline(deadline) {
+                Ok(envelope) => envelope,
+                Err(RecvTimeoutError::Timeout) => {
+                    watermark = watermark.max(clock.now());
+                    if !close(ctx, &aggregate, &mut open, watermark, &outbound)? {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            log::enter_item(Some(id));
+            event!(Level::Trace, "windowing {:?}", item);
+            let added = ctx.metrics.track(
+                || {
+                    ctx.guard(None, || {
+                        let (time, lateness) = match &window.event_time {
+                            Some(event_time) => (event_time(&item), window.allowed_lateness),
+                            None => (clock.now(), Duration::ZERO),
+                        };
+                        let added = add(&window, &aggregate, &mut open, watermark, id, time, &item);
+                        if !added {
+                            let late = watermark.saturating_sub(time);
+                            ctx.dead_letter(&format!("{:?}", item), StageErrorKind::Late(late));
+                        }
+                        watermark = watermark.max(time.saturating_sub(lateness));
+                        added
+                    })
+                },
+                |added| Some(matches!(added, Ok(Some(true)))),
+            )?;
+            if added.is_none() {
+                continue;
+            }
+            if !close(ctx, &aggregate, &mut open, watermark, &outbound)? {
+                return Ok(());
+            }
+        }
+        // Upstream is done: every window left goes out as it is, unless the pipeline was cancelled.
+        if !ctx.token.is_cancelled() {
+            close(ctx, &aggregate, &mut open, Duration::MAX, &outbound)?;
+        }
+        Ok(())
+    })
+}
+
+/// Put `item`, which happened at `time`, into the windows it falls in that are still open.
+/// Returns `false` if they are all closed already.
+fn add<T, A: Aggregate<T>>(
+    window: &Window<T>,
+    aggregate: &A,
+    open: &mut BTreeMap<Duration, Open<A::Acc>>,
+    watermark: Duration,
+    id: ItemId,
+    time: Duration,
+    item: &T,
+) -> bool {
+    let (size, slide) = match window.shape {
+        Shape::Tumbling { size } => (size, size),
+        Shape::Sliding { size, slide } => (size, slide),
+        Shape::Session { gap } => {
+            return add_to_session(aggregate, open, watermark, id, time, gap, item)
+        }
+    };
+    let mut added = false;
+    let mut start = align(time, slide);
+    while start + size > time {
+        let end = start + size;
+        if end > watermark {
+            added = true;
+            match open.entry(start) {
+                Entry::Vacant(entry) => {
+                    entry.insert(Open {
+                        end,
+                        id,
+                        acc: aggregate.start(item),
+                    });
+                }
+                Entry::Occupied(mut entry) => aggregate.add(&mut entry.get_mut().acc, item),
+            }
+        }
+        let Some(previous) = start.checked_sub(slide) else {
+            break;
+        };
+        start = previous;
+    }
+    added
+}
+
+/// Open a session for `item` and merge it with every open session it overlaps.
+fn add_to_session<T, A: Aggregate<T>>(
+    aggregate: &A,
+    open: &mut BTreeMap<Duration, Open<A::Acc>>,
+    watermark: Duration,
+    id: ItemId,
+    time: Duration,
+    gap: Duration,
+    item: &T,
+) -> bool {
+    let end = time + gap;
+    if end <= watermark {
+        return false;
+    }
+    let overlapping: Vec<_> = open
+        .range(..end)
+        .filter(|(_, session)| session.end > time)
+        .map(|(&start, _)| start)
+        .collect();
+    let mut start = time;
+    let mut session = Open {
+        end,
+        id,
+        acc: aggregate.start(item),
+    };
+    for key in overlapping {
+        let Some(other) = open.remove(&key) else {
+            continue;
+        };
+        start = start.min(key);
+        session.end = session.end.max(other.end);
+        session.id = session.id.min(other.id);
+        aggregate.merge(&mut session.acc, other.acc);
+    }
+    open.insert(start, session);
+    true
+}
+
+/// Send downstream the windows ending at or before `watermark`, in order.
+/// Returns `false` once the stage should stop, downstream being gone.
+fn close<T, A: Aggregate<T>>(
+    ctx: &StageCtx,
+    aggregate: &A,
+    open: &mut BTreeMap<Duration, Open<A::Acc>>,
+    watermark: Duration,
+    outbound: &Outbound<Envelope<Windowed<A::Output>>>,
+) -> Result<bool, PipelineError> {
+    while let Some(first) = open.first_entry() {
+        if first.get().end > watermark {
+            break;
+        }
+        let (start, Open { end, id, acc }) = first.remove_entry();
+        log::enter_item(Some(id));
+        let Some(value) = ctx.guard(None, || aggregate.finish(acc))? else {
+            continue;
+        };
+        event!(
+            Level::Trace,
+            "closing window {:?}..{:?}: {:?}",
+            start,
+            end,
+            value
+        );
+        let windowed = Windowed { start, end, value };
+        if !ctx.forward(outbound, Envelope { id, item: windowed }) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,367 @@
+use std::fmt::{Debug, Display, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+
+use crate::cancel::CancellationToken;
+use crate::error::{panic_message, PipelineError, StageError, StageErrorKind};
+use crate::event;
+use crate::fan_out::WorkerPool;
+use crate::link::{link, Envelope, Inbound, LinkConfig, LinkMetrics, Outbound, SendError};
+use crate::log::{self, Level, Logger};
+use crate::metrics::{Metrics, StageMetrics};
+use crate::stage::{PanicPolicy, Stage, StageConfig};
+use crate::watchdog::{self, Activity, Probe};
+
+pub(crate) type StageThread = (String, JoinHandle<Result<(), PipelineError>>);
+
+/// What stages get wired with while a pipeline is being spawned.
+/// It is kept around by the stages that can spawn more threads while running,
+/// such as fan-out pools, so all of its registries are shared.
+#[derive(Clone)]
+pub(crate) struct Wiring {
+    pub(crate) token: CancellationToken,
+    errors: Sender<StageError>,
+    logger: Arc<dyn Logger>,
+    pub(crate) threads: Arc<Mutex<Vec<StageThread>>>,
+    pub(crate) links: Arc<Mutex<Vec<Arc<LinkMetrics>>>>,
+    pub(crate) stages: Arc<Mutex<Vec<Arc<StageMetrics>>>>,
+    pub(crate) probes: Arc<Mutex<Vec<Arc<Probe>>>>,
+    pub(crate) pools: Arc<Mutex<Vec<WorkerPool>>>,
+}
+
+impl Wiring {
+    pub(crate) fn new(
+        token: CancellationToken,
+        errors: Sender<StageError>,
+        logger: Arc<dyn Logger>,
+    ) -> Self {
+        Wiring {
+            token,
+            errors,
+            logger,
+            threads: Arc::default(),
+            links: Arc::default(),
+            stages: Arc::default(),
+            probes: Arc::default(),
+            pools: Arc::default(),
+        }
+    }
+
+    pub(crate) fn link<T>(&self, name: String, config: LinkConfig) -> (Outbound<T>, Inbound<T>) {
+        let (tx, rx) = link(name, config, &self.token);
+        lock(&self.links).push(rx.metrics());
+        (tx, rx)
+    }
+
+    /// Run `body` on a new thread named after the stage, within the stage's log span.
+    /// A panic escaping `body` is reported and fails the whole pipeline.
+    pub(crate) fn spawn<F>(&self, config: &StageConfig, body: F) -> Result<(), PipelineError>
+    where
+        F: FnOnce(&StageCtx) -> Result<(), PipelineError> + Send + 'static,
+    {
+        let ctx = self.ctx(config);
+        let logger = self.logger.clone();
+        let thread = thread::Builder::new()
+            .name(config.name.clone())
+            .spawn(move || {
+                log::enter_stage(&ctx.name, logger);
+                watchdog::attach(Some(ctx.probe.clone()));
+                event!(Level::Debug, "started");
+                let result = match panic::catch_unwind(AssertUnwindSafe(|| body(&ctx))) {
+                    Ok(result) => result,
+                    Err(payload) => Err(ctx.fail(None, panic_message(payload.as_ref()))),
+                };
+                log::enter_item(None);
+                match &result {
+                    Ok(()) => event!(Level::Debug, "stopped"),
+                    Err(err) => event!(Level::Error, "stopped: {}", err),
+                }
+                ctx.probe.set(Activity::Stopped);
+                result
+            })
+            .map_err(|source| PipelineError::Spawn {
+                stage: config.name.clone(),
+                source,
+            })?;
+        lock(&self.threads).push((config.name.clone(), thread));
+        Ok(())
+    }
+
+    /// The context of a new stage, whose metrics and probe are registered right away.
+    pub(crate) fn ctx(&self, config: &StageConfig) -> StageCtx {
+        let metrics = Arc::new(StageMetrics::new(config.name.clone()));
+        lock(&self.stages).push(metrics.clone());
+        let probe = Arc::new(Probe::new(config.name.clone()));
+        lock(&self.probes).push(probe.clone());
+        StageCtx {
+            name: config.name.clone(),
+            metrics,
+            probe,
+            on_panic: config.on_panic,
+            describe_items: config.describe_items,
+            token: self.token.clone(),
+            errors: self.errors.clone(),
+            dead_letters: config.dead_letters.clone(),
+        }
+    }
+
+    pub(crate) fn logger(&self) -> Arc<dyn Logger> {
+        self.logger.clone()
+    }
+
+    pub(crate) fn metrics(&self) -> Metrics {
+        Metrics {
+            stages: self.stages.clone(),
+            links: self.links.clone(),
+        }
+    }
+}
+
+/// Lock `mutex`, ignoring poisoning: the registries and queues it guards
+/// are never left half-updated by a panic.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// What a stage thread knows about itself and the pipeline it belongs to.
+pub(crate) struct StageCtx {
+    pub(crate) name: String,
+    pub(crate) metrics: Arc<StageMetrics>,
+    pub(crate) probe: Arc<Probe>,
+    on_panic: PanicPolicy,
+    /// Whether items are described before going into the stage, see `StageConfig::describe_items`.
+    pub(crate) describe_items: bool,
+    pub(crate) token: CancellationToken,
+    errors: Sender<StageError>,
+    dead_letters: Option<Sender<StageError>>,
+}
+
+impl StageCtx {
+    /// Run `f`, isolating any panic according to the stage's panic policy.
+    /// `item` describes what is being processed, for the error report, if that is known.
+    ///
+    /// Returns `Ok(None)` if `f` panicked but the stage should keep going.
+    pub(crate) fn guard<R>(
+        &self,
+        item: Option<&str>,
+        f: impl FnOnce() -> R,
+    ) -> Result<Option<R>, PipelineError> {
+        self.caught(item, panic::catch_unwind(AssertUnwindSafe(f)))
+    }
+
+    /// Same as `guard`, for a panic that was already caught.
+    pub(crate) fn caught<R>(
+        &self,
+        item: Option<&str>,
+        result: thread::Result<R>,
+    ) -> Result<Option<R>, PipelineError> {
+        match result {
+            Ok(out) => Ok(Some(out)),
+            Err(payload) => {
+                let message = panic_message(payload.as_ref());
+                match self.on_panic {
+                    PanicPolicy::Restart => {
+                        self.report(item, StageErrorKind::Panicked(message));
+                        Ok(None)
+                    }
+                    PanicPolicy::FailPipeline => Err(self.fail(item, message)),
+                }
+            }
+        }
+    }
+
+    pub(crate) fn report(&self, item: Option<&str>, kind: StageErrorKind) {
+        let error = StageError {
+            stage: self.name.clone(),
+            id: log::current_item(),
+            item: item.map(str::to_owned),
+            kind,
+        };
+        event!(Level::Warn, "{}", error);
+        // Nobody listening for errors is not an error in itself.
+        let _ = self.errors.send(error);
+    }
+
+    /// Send `item` downstream, reporting it if it had to be dropped because the link was full.
+    /// Returns `false` once the stage should stop, downstream being gone or cut off.
+    pub(crate) fn forward<T>(&self, outbound: &Outbound<T>, item: T) -> bool {
+        match outbound.send(item) {
+            Ok(()) => true,
+            Err(SendError::Full(_)) => {
+                self.report(None, StageErrorKind::LinkFull);
+                true
+            }
+            Err(SendError::Disconnected(_)) => {
+                self.report(None, StageErrorKind::Disconnected);
+                false
+            }
+            Err(SendError::Closed(_)) => false,
+        }
+    }
+
+    /// Report an item rejected by a fallible stage, to the stage's dead letters if any.
+    pub(crate) fn reject(&self, item: &str, message: String) {
+        self.dead_letter(item, StageErrorKind::Rejected(message));
+    }
+
+    /// Report an item the stage gave up on, to the stage's dead letters if any.
+    pub(crate) fn dead_letter(&self, item: &str, kind: StageErrorKind) {
+        let error = StageError {
+            stage: self.name.clone(),
+            id: log::current_item(),
+            item: Some(item.to_owned()),
+            kind,
+        };
+        event!(Level::Warn, "{}", error);
+        let _ = match &self.dead_letters {
+            Some(dead_letters) => dead_letters.send(error),
+            None => self.errors.send(error),
+        };
+    }
+
+    /// Report a panic and cancel the pipeline.
+    pub(crate) fn fail(&self, item: Option<&str>, message: String) -> PipelineError {
+        self.report(item, StageErrorKind::Panicked(message.clone()));
+        self.token.cancel();
+        PipelineError::Panicked {
+            stage: self.name.clone(),
+            message,
+        }
+    }
+}
+
+/// Decides what becomes of a stage's output, given the description of the input it came from:
+/// `Some` is sent downstream, `None` is dropped.
+pub(crate) struct Route<Out, U> {
+    route: fn(&StageCtx, &str, Out) -> Option<U>,
+    /// Whether the input may be reported. It has to be described before the stage
+    /// takes it then, and the description is left empty otherwise.
+    reports: bool,
+}
+
+impl<Out, U> Route<Out, U> {
+    /// Describe `item` into `repr` if it may be reported, or `always`, before it goes
+    /// into the stage. Returns whether it was.
+    pub(crate) fn describe(&self, repr: &mut String, item: &impl Debug, always: bool) -> bool {
+        repr.clear();
+        let describe = self.reports || always;
+        if describe {
+            let _ = write!(repr, "{:?}", item);
+        }
+        describe
+    }
+
+    pub(crate) fn apply(&self, ctx: &StageCtx, item: &str, out: Out) -> Option<U> {
+        (self.route)(ctx, item, out)
+    }
+}
+
+impl<Out, U> Clone for Route<Out, U> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Out, U> Copy for Route<Out, U> {}
+
+pub(crate) fn pass<U>() -> Route<U, U> {
+    Route {
+        route: |_, _, out| Some(out),
+        reports: false,
+    }
+}
+
+pub(crate) fn reject<U, E: Display>() -> Route<Result<U, E>, U> {
+    Route {
+        route: |ctx, item, out| out.map_err(|err| ctx.reject(item, err.to_string())).ok(),
+        reports: true,
+    }
+}
+
+pub(crate) fn spawn_worker<In, Out, U, S>(
+    wiring: &Wiring,
+    config: &StageConfig,
+    mut stage: S,
+    route: Route<Out, U>,
+    inbound: Inbound<Envelope<In>>,
+    outbound: Outbound<Envelope<U>>,
+) -> Result<(), PipelineError>
+where
+    In: Send + Debug + 'static,
+    Out: 'static,
+    U: Send + 'static,
+    S: Stage<In, Out>,
+{
+    wiring.spawn(config, move |ctx| {
+        let mut repr = String::new();
+        while let Some(Envelope { id, item }) = inbound.recv() {
+            log::enter_item(Some(id));
+            let Some(item) = process(ctx, &mut stage, route, &mut repr, item)? else {
+                continue;
+            };
+            if !ctx.forward(&outbound, Envelope { id, item }) {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Same as `spawn_worker`, for numbered items. Every number makes it downstream,
+/// with `None` in place of the items that were dropped.
+pub(crate) fn spawn_ordered_worker<In, Out, U, S>(
+    wiring: &Wiring,
+    config: &StageConfig,
+    mut stage: S,
+    route: Route<Out, U>,
+    inbound: Inbound<(u64, Envelope<In>)>,
+    outbound: Outbound<(u64, Option<Envelope<U>>)>,
+) -> Result<(), PipelineError>
+where
+    In: Send + Debug + 'static,
+    Out: 'static,
+    U: Send + 'static,
+    S: Stage<In, Out>,
+{
+    wiring.spawn(config, move |ctx| {
+        let mut repr = String::new();
+        while let Some((seq, Envelope { id, item })) = inbound.recv() {
+            log::enter_item(Some(id));
+            let out = process(ctx, &mut stage, route, &mut repr, item)?;
+            let out = out.map(|item| Envelope { id, item });
+            if !ctx.forward(&outbound, (seq, out)) {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Run `item` through `stage` then `route`. `Ok(None)` if it was dropped on the way.
+fn process<In, Out, U, S>(
+    ctx: &StageCtx,
+    stage: &mut S,
+    route: Route<Out, U>,
+    repr: &mut String,
+    item: In,
+) -> Result<Option<U>, PipelineError>
+where
+    In: Debug,
+    S: Stage<In, Out>,
+{
+    event!(Level::Trace, "processing {:?}", item);
+    // The item is moved into the stage, so a panic can only tell what was described beforehand.
+    let described = route.describe(repr, &item, ctx.describe_items);
+    let repr: &str = repr;
+    ctx.metrics.track(
+        || {
+            let Some(out) = ctx.guard(described.then_some(repr), || stage.process(item))? else {
+                return Ok(None);
+            };
+            Ok(route.apply(ctx, repr, out))
+        },
+        |out| Some(matches!(out, Ok(Some(_)))),
+    )
+}
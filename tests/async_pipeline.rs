@@ -0,0 +1,62 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rconcurrency_stuff::{block_on, AsyncPipeline, FanOut, Source, ThreadPool};
+
+fn thread_name() -> String {
+    thread::current().name().unwrap_or_default().to_owned()
+}
+
+#[test]
+fn pipelines_run_on_a_thread_pool() {
+    let pool = ThreadPool::new(2).unwrap();
+    let mut handle = AsyncPipeline::source("numbers", Source::new(0..20u64))
+        .fan_out("square", FanOut::new().workers(3), |num: u64| async move {
+            (num * num, thread_name())
+        })
+        .try_stage("odd", |(num, thread): (u64, String)| async move {
+            if num % 2 == 1 {
+                Ok((num, thread))
+            } else {
+                Err(format!("{} is even", num))
+            }
+        })
+        .spawn(&pool);
+
+    let mut outputs = Vec::new();
+    while let Some((num, thread)) = block_on(handle.recv()) {
+        assert!(thread.starts_with("executor-"), "{} ran on {}", num, thread);
+        outputs.push(num);
+    }
+    outputs.sort_unstable();
+    let odd: Vec<_> = (0..20u64)
+        .map(|num| num * num)
+        .filter(|num| num % 2 == 1)
+        .collect();
+    assert_eq!(outputs, odd);
+    assert_eq!(handle.errors().count(), 10);
+    for report in block_on(handle.join()) {
+        assert!(report.result.is_ok(), "{} failed", report.stage);
+    }
+}
+
+#[test]
+fn rate_limited_sources_sleep_without_blocking_the_pool() {
+    // A single thread: the source sleeping between items must not hold it up.
+    let pool = ThreadPool::new(1).unwrap();
+    let started = Instant::now();
+    let mut handle = AsyncPipeline::source("numbers", Source::new(0..5u32).rate_limit(50))
+        .stage("double", |num: u32| async move { num * 2 })
+        .spawn(&pool);
+
+    let mut outputs = Vec::new();
+    while let Some(num) = block_on(handle.recv()) {
+        outputs.push(num);
+    }
+    assert_eq!(outputs, [0, 2, 4, 6, 8]);
+    // Four gaps of 20ms between five items.
+    assert!(started.elapsed() >= Duration::from_millis(80));
+    for report in block_on(handle.join()) {
+        assert!(report.result.is_ok(), "{} failed", report.stage);
+    }
+}
@@ -0,0 +1,74 @@
+use std::thread;
+use std::time::Duration;
+
+use rconcurrency_stuff::sink::Collect;
+use rconcurrency_stuff::{Batch, FanOut, Pipeline, Source};
+
+#[test]
+fn batches_are_cut_by_count() {
+    let batches = Pipeline::source("generate", Source::new(1..=7u32))
+        .batch("batch", Batch::new().max_items(3))
+        .run(Collect::new())
+        .unwrap();
+    assert_eq!(batches, [vec![1, 2, 3], vec![4, 5, 6], vec![7]]);
+}
+
+#[test]
+fn batches_are_cut_by_size() {
+    let frames = ["ab", "cd", "efg", "hijklm", "n"].map(str::to_owned);
+    let batches = Pipeline::source("generate", Source::new(frames))
+        .batch(
+            "batch",
+            Batch::new().max_bytes(5, |frame: &String| frame.len()),
+        )
+        .run(Collect::new())
+        .unwrap();
+    assert_eq!(
+        batches,
+        [vec!["ab", "cd"], vec!["efg"], vec!["hijklm"], vec!["n"]]
+    );
+}
+
+#[test]
+fn batches_go_out_after_their_max_latency() {
+    let pipeline = Pipeline::source(
+        "generate",
+        Source::from_fn({
+            let mut next = 0u32;
+            move || {
+                next += 1;
+                // Two items right away, then nothing for a while.
+                if next == 3 {
+                    thread::sleep(Duration::from_millis(500));
+                }
+                (next <= 3).then_some(next)
+            }
+        }),
+    )
+    .batch(
+        "batch",
+        Batch::new()
+            .max_items(10)
+            .max_latency(Duration::from_millis(20)),
+    )
+    .spawn()
+    .unwrap();
+    assert_eq!(pipeline.recv(), Some(vec![1, 2]));
+    assert_eq!(pipeline.recv(), Some(vec![3]));
+    assert_eq!(pipeline.recv(), None);
+}
+
+#[test]
+fn workers_process_whole_batches() {
+    let squares = Pipeline::source("generate", Source::new(0..100u64))
+        .batch("batch", Batch::new().max_items(8))
+        .fan_out(
+            "square",
+            FanOut::new().workers(3).ordered(6),
+            |batch: Vec<u64>| batch.into_iter().map(|num| num * num).collect::<Vec<_>>(),
+        )
+        .unbatch("unbatch")
+        .run(Collect::new())
+        .unwrap();
+    assert_eq!(squares, (0..100).map(|num| num * num).collect::<Vec<_>>());
+}
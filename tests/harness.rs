@@ -0,0 +1,253 @@
+use std::future;
+
+use rconcurrency_stuff::dispatch::SharedQueue;
+use rconcurrency_stuff::error::StageErrorKind;
+use rconcurrency_stuff::log::ItemId;
+use rconcurrency_stuff::testing::{Blocking, Harness};
+use rconcurrency_stuff::watchdog::Activity;
+use rconcurrency_stuff::{AsyncPipeline, FanOut, PanicPolicy, PipelineError, StageConfig};
+
+fn square(input: AsyncPipeline<u32>) -> AsyncPipeline<u32> {
+    input.stage("square", |num: u32| async move { num * num })
+}
+
+#[test]
+fn outputs_come_out_in_order() {
+    let mut harness =
+        Harness::new(|input| square(input).stage("add", |num| async move { num + 1 }));
+    for num in 1..=5 {
+        assert!(harness.push(num));
+    }
+    assert_eq!(harness.run(), [2, 5, 10, 17, 26]);
+
+    let finished = harness.finish().unwrap();
+    assert!(finished.outputs.is_empty());
+    assert!(finished.errors.is_empty());
+    let stages: Vec<_> = finished
+        .reports
+        .iter()
+        .map(|report| report.stage.as_str())
+        .collect();
+    assert_eq!(stages, ["square", "add"]);
+    assert!(finished.reports.iter().all(|report| report.result.is_ok()));
+}
+
+#[test]
+fn nothing_runs_until_asked_to() {
+    let mut harness = Harness::new(square);
+    harness.push(2);
+    assert_eq!(harness.executor().pending(), 1);
+    assert_eq!(harness.run(), [4]);
+    assert_eq!(harness.run(), []);
+    harness.push(3);
+    harness.push(4);
+    assert_eq!(harness.run(), [9, 16]);
+    assert_eq!(harness.executor().pending(), 1);
+    harness.finish().unwrap();
+}
+
+#[test]
+fn sync_stages_step_through_on_the_calling_thread() {
+    let mut total = 0;
+    let running_total = move |num: u32| {
+        total += num;
+        total
+    };
+    let mut harness = Harness::new(|input| {
+        input
+            .stage("total", Blocking(running_total))
+            .stage("square", Blocking(|num: u32| num * num))
+    });
+    harness.push(1);
+    harness.push(2);
+    assert_eq!(harness.run(), [1, 9]);
+    harness.push(3);
+    assert_eq!(harness.run(), [36]);
+    assert!(harness.finish().unwrap().errors.is_empty());
+}
+
+#[test]
+fn fan_out_runs_the_same_way_every_time() {
+    let outputs = || {
+        let mut harness = Harness::new(|input| {
+            let fan_out = FanOut::new().workers(3).dispatcher(SharedQueue);
+            input.fan_out("square", fan_out, |num: u32| async move {
+                // Uneven work, so the workers don't finish in the order they started.
+                if num % 2 == 0 {
+                    rconcurrency_stuff::executor::yield_now().await;
+                }
+                num * num
+            })
+        });
+        (1..=10).for_each(|num| {
+            harness.push(num);
+        });
+        harness.finish().unwrap().outputs
+    };
+    let first = outputs();
+    assert_eq!(first, outputs());
+
+    let mut sorted = first.clone();
+    sorted.sort_unstable();
+    assert_eq!(sorted, (1..=10).map(|num| num * num).collect::<Vec<_>>());
+}
+
+#[test]
+fn rejected_items_are_reported() {
+    let mut harness = Harness::new(|input| {
+        input.try_stage("halve", |num: u32| async move {
+            if num % 2 == 0 {
+                Ok(num / 2)
+            } else {
+                Err(format!("{} is odd", num))
+            }
+        })
+    });
+    for num in 1..=4 {
+        harness.push(num);
+    }
+    let finished = harness.finish().unwrap();
+    assert_eq!(finished.outputs, [1, 2]);
+    let rejected: Vec<_> = finished
+        .errors
+        .iter()
+        .map(|error| (error.item.as_deref(), &error.kind))
+        .collect();
+    assert!(matches!(
+        rejected[..],
+        [
+            (Some("1"), StageErrorKind::Rejected(_)),
+            (Some("3"), StageErrorKind::Rejected(_)),
+        ]
+    ));
+}
+
+#[test]
+fn panics_only_lose_the_item_when_restarting() {
+    let mut harness = Harness::new(|input| {
+        input.stage(
+            StageConfig::new("square")
+                .on_panic(PanicPolicy::Restart)
+                .describe_items(),
+            |num: u32| async move {
+                assert_ne!(num, 2, "no twos");
+                num * num
+            },
+        )
+    });
+    for num in 1..=3 {
+        harness.push(num);
+    }
+    let finished = harness.finish().unwrap();
+    assert_eq!(finished.outputs, [1, 9]);
+    assert_eq!(finished.errors.len(), 1);
+    assert!(matches!(
+        finished.errors[0].kind,
+        StageErrorKind::Panicked(_)
+    ));
+    // The item was described before it went into the stage.
+    assert_eq!(finished.errors[0].id, Some(ItemId(1)));
+    assert_eq!(finished.errors[0].item.as_deref(), Some("2"));
+}
+
+#[test]
+fn panics_fail_the_pipeline_by_default() {
+    let mut harness = Harness::new(|input| {
+        input
+            .stage("check", |num: u32| async move {
+                assert_ne!(num, 2, "no twos");
+                num
+            })
+            .stage("square", |num: u32| async move { num * num })
+    });
+    harness.push(1);
+    assert_eq!(harness.run(), [1]);
+    harness.push(2);
+    harness.push(3);
+    let finished = harness.finish().unwrap();
+    assert!(finished.outputs.is_empty());
+    assert!(matches!(
+        finished.reports[0].result,
+        Err(PipelineError::Panicked { .. })
+    ));
+    assert!(finished.reports[1].result.is_ok());
+}
+
+#[test]
+fn ordered_fan_out_keeps_the_input_order() {
+    for fan_out in [
+        FanOut::new().workers(3).ordered(2),
+        FanOut::new().workers(3).ordered(4).dispatcher(SharedQueue),
+    ] {
+        let mut harness = Harness::new(|input| {
+            input.fan_out("square", fan_out, |num: u32| async move {
+                // The later items are done first.
+                for _ in num..10 {
+                    rconcurrency_stuff::executor::yield_now().await;
+                }
+                num * num
+            })
+        });
+        (1..=10).for_each(|num| {
+            harness.push(num);
+        });
+        let outputs = harness.finish().unwrap().outputs;
+        assert_eq!(outputs, (1..=10).map(|num| num * num).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn shutdown_stops_every_stage() {
+    let mut harness = Harness::new(|input| {
+        let fan_out = FanOut::new().workers(2).dispatcher(SharedQueue);
+        square(input).fan_out("add", fan_out, |num| async move { num + 1 })
+    });
+    harness.push(1);
+    assert_eq!(harness.run(), [2]);
+    harness.push(2);
+    let finished = harness.shutdown().unwrap();
+    assert!(finished.outputs.is_empty());
+    assert_eq!(finished.reports.len(), 3);
+    assert!(finished.reports.iter().all(|report| report.result.is_ok()));
+}
+
+#[test]
+fn items_pushed_after_close_are_dropped() {
+    let mut harness = Harness::new(square);
+    harness.close();
+    assert!(!harness.push(1));
+    assert!(harness.finish().unwrap().outputs.is_empty());
+}
+
+#[test]
+fn stages_that_never_exit_are_caught() {
+    let mut harness = Harness::new(|input| {
+        square(input).stage("stuck", |num: u32| async move {
+            if num == 4 {
+                future::pending::<()>().await;
+            }
+            num
+        })
+    });
+    for num in 1..=3 {
+        harness.push(num);
+    }
+    assert_eq!(harness.run(), [1]);
+    let leak = harness.finish().unwrap_err();
+    assert_eq!(leak.stages, ["stuck"]);
+}
+
+#[test]
+fn idle_stages_are_waiting_for_input() {
+    let mut harness = Harness::new(square);
+    harness.push(2);
+    assert_eq!(harness.run(), [4]);
+    let status = harness.handle().status();
+    assert_eq!(
+        status[0].activity,
+        Activity::Receiving {
+            link: "input".to_owned()
+        }
+    );
+    harness.finish().unwrap();
+}
@@ -0,0 +1,104 @@
+use std::sync::{Arc, Mutex};
+
+use rconcurrency_stuff::event;
+use rconcurrency_stuff::log::{ItemId, Record};
+use rconcurrency_stuff::sink::Collect;
+use rconcurrency_stuff::{Level, Logger, Pipeline, Source};
+
+/// An event as the logger got it.
+type Captured = (Level, String, Option<ItemId>, String);
+
+/// Keeps every event of `max_level` and above.
+#[derive(Clone)]
+struct Capture {
+    max_level: Level,
+    events: Arc<Mutex<Vec<Captured>>>,
+}
+
+impl Capture {
+    fn new(max_level: Level) -> Self {
+        Capture {
+            max_level,
+            events: Arc::default(),
+        }
+    }
+
+    fn events(&self) -> Vec<Captured> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl Logger for Capture {
+    fn enabled(&self, level: Level) -> bool {
+        level <= self.max_level
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        self.events.lock().unwrap().push((
+            record.level,
+            record.stage.to_owned(),
+            record.item,
+            record.message.to_string(),
+        ));
+    }
+}
+
+fn run_logged(logger: &Capture) -> Vec<u64> {
+    Pipeline::source("generate", Source::new(1..=3u64))
+        .logger(logger.clone())
+        .stage("square", |num: u64| {
+            event!(Level::Info, "squaring {}", num);
+            event!(Level::Debug, "checking {}", num);
+            num * num
+        })
+        .run(Collect::new())
+        .unwrap()
+}
+
+#[test]
+fn events_carry_their_stage_and_item() {
+    let logger = Capture::new(Level::Info);
+    assert_eq!(run_logged(&logger), [1, 4, 9]);
+    // Events logged outside of a stage go nowhere.
+    event!(Level::Error, "not in a stage");
+
+    let events = logger.events();
+    assert!(events.iter().all(|(level, ..)| *level <= Level::Info));
+    let squaring: Vec<_> = events
+        .into_iter()
+        .filter(|(_, stage, ..)| stage == "square")
+        .map(|(level, _, item, message)| (level, item, message))
+        .collect();
+    assert_eq!(
+        squaring,
+        [
+            (Level::Info, Some(ItemId(0)), "squaring 1".to_owned()),
+            (Level::Info, Some(ItemId(1)), "squaring 2".to_owned()),
+            (Level::Info, Some(ItemId(2)), "squaring 3".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn lower_levels_are_filtered_out() {
+    let debug = Capture::new(Level::Debug);
+    run_logged(&debug);
+    let logged: Vec<_> = debug
+        .events()
+        .into_iter()
+        .filter(|(_, stage, item, _)| stage == "square" && *item == Some(ItemId(0)))
+        .map(|(level, _, _, message)| (level, message))
+        .collect();
+    assert_eq!(
+        logged,
+        [
+            (Level::Info, "squaring 1".to_owned()),
+            (Level::Debug, "checking 1".to_owned()),
+        ]
+    );
+
+    // Nothing goes wrong, so there is nothing to log at all.
+    let errors = Capture::new(Level::Error);
+    run_logged(&errors);
+    assert_eq!(errors.events(), []);
+}
@@ -0,0 +1,319 @@
+use std::ops::{ControlFlow, Range};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rconcurrency_stuff::log::ItemId;
+use rconcurrency_stuff::sink::{Collect, Fold, Reduce, WriteTo};
+use rconcurrency_stuff::{
+    Backpressure, FanOut, PanicPolicy, Pipeline, PipelineError, Sink, Source, StageConfig,
+    StageErrorKind,
+};
+
+#[test]
+fn run_returns_the_sink_output() {
+    let squares = Pipeline::source("generate", Source::new(1..=5u32))
+        .stage("square", |num: u32| num * num)
+        .run(Collect::new())
+        .unwrap();
+    assert_eq!(squares, [1, 4, 9, 16, 25]);
+
+    let sum = Pipeline::source("generate", Source::new(1..=5u32))
+        .run(Fold::new(0, |sum, num| sum + num))
+        .unwrap();
+    assert_eq!(sum, Some(15));
+
+    let max = Pipeline::source("generate", Source::new(Vec::<u32>::new()))
+        .run(Reduce::new(u32::max))
+        .unwrap();
+    assert_eq!(max, None);
+
+    let written = Pipeline::source("generate", Source::new(1..=3u32))
+        .run(WriteTo::lines(Vec::new()))
+        .unwrap()
+        .unwrap();
+    assert_eq!(written, b"1\n2\n3\n");
+}
+
+#[test]
+fn ordered_fan_out_keeps_the_input_order() {
+    let squares = Pipeline::source("generate", Source::new(0..100u64))
+        .fan_out("square", FanOut::new().workers(4).ordered(8), |num: u64| {
+            // Uneven work, so the workers don't finish in the order they started.
+            thread::sleep(Duration::from_micros(num % 7 * 100));
+            num * num
+        })
+        .run(Collect::new())
+        .unwrap();
+    assert_eq!(squares, (0..100).map(|num| num * num).collect::<Vec<_>>());
+}
+
+struct FirstThree(Vec<u64>);
+
+impl Sink<u64> for FirstThree {
+    type Output = Vec<u64>;
+
+    fn consume(&mut self, item: u64) -> ControlFlow<()> {
+        self.0.push(item);
+        if self.0.len() < 3 {
+            ControlFlow::Continue(())
+        } else {
+            ControlFlow::Break(())
+        }
+    }
+
+    fn finish(self) -> Vec<u64> {
+        self.0
+    }
+}
+
+#[test]
+fn a_sink_stopping_early_stops_upstream() {
+    let first = Pipeline::source("generate", Source::new(0..))
+        .fan_out("square", FanOut::new().workers(2).ordered(2), |num: u64| {
+            num * num
+        })
+        .run(FirstThree(Vec::new()))
+        .unwrap();
+    assert_eq!(first, [0, 1, 4]);
+}
+
+#[test]
+fn rejected_items_go_to_dead_letters() {
+    let (dead_letters, rejected) = channel();
+    let halves = Pipeline::source("generate", Source::new(1..=4u32))
+        .try_stage(
+            StageConfig::new("halve").dead_letters(dead_letters),
+            |num: u32| match num % 2 {
+                0 => Ok(num / 2),
+                _ => Err("odd"),
+            },
+        )
+        .run(Collect::new())
+        .unwrap();
+    assert_eq!(halves, [1, 2]);
+    let items: Vec<_> = rejected.try_iter().map(|error| error.item).collect();
+    assert_eq!(items, [Some("1".to_owned()), Some("3".to_owned())]);
+}
+
+#[test]
+fn panics_fail_the_run() {
+    let result = Pipeline::source("generate", Source::new(1..=3u32))
+        .stage("check", |num: u32| {
+            assert_ne!(num, 2, "no twos");
+            num
+        })
+        .run(Collect::new());
+    assert!(matches!(result, Err(PipelineError::Panicked { stage, .. }) if stage == "check"));
+}
+
+#[test]
+fn panics_are_reported_with_the_item_when_described() {
+    let pipeline = Pipeline::source("generate", Source::new(1..=3u32))
+        .stage(
+            StageConfig::new("check").on_panic(PanicPolicy::Restart),
+            |num: u32| {
+                assert_ne!(num, 2, "no twos");
+                num
+            },
+        )
+        .stage(
+            StageConfig::new("square")
+                .on_panic(PanicPolicy::Restart)
+                .describe_items(),
+            |num: u32| {
+                assert_ne!(num, 3, "no threes");
+                num * num
+            },
+        )
+        .spawn()
+        .unwrap();
+    assert_eq!(pipeline.iter().collect::<Vec<_>>(), [1]);
+    let errors: Vec<_> = pipeline.errors().collect();
+    let reported: Vec<_> = errors
+        .iter()
+        .map(|error| (error.stage.as_str(), error.id, error.item.as_deref()))
+        .collect();
+    // The item that went into a stage not describing them is only known by its id.
+    assert_eq!(
+        reported,
+        [
+            ("check", Some(ItemId(1)), None),
+            ("square", Some(ItemId(2)), Some("3")),
+        ]
+    );
+    assert!(errors[1].to_string().ends_with("(item #2: 3)"));
+}
+
+#[test]
+fn panicking_sources_stop() {
+    let pipeline = Pipeline::source(
+        StageConfig::new("generate").on_panic(PanicPolicy::Restart),
+        Source::from_fn(|| -> Option<u64> { panic!("out of order") }),
+    )
+    .spawn()
+    .unwrap();
+    assert_eq!(pipeline.recv(), None);
+    let errors: Vec<_> = pipeline.errors().collect();
+    assert!(matches!(&errors[..], [error] if matches!(error.kind, StageErrorKind::Panicked(_))));
+    for report in pipeline.join() {
+        report.result.unwrap();
+    }
+}
+
+fn add_all_but_three(sum: u32, num: u32) -> u32 {
+    assert_ne!(num, 3, "no threes");
+    sum + num
+}
+
+#[test]
+fn panicking_folds_lose_their_output() {
+    let pipeline = Pipeline::source("generate", Source::new(1..=5u32))
+        .sink(
+            StageConfig::new("sum").on_panic(PanicPolicy::Restart),
+            Fold::new(0, add_all_but_three),
+        )
+        .spawn()
+        .unwrap();
+    // The sink stopped at the panic, rather than the pipeline looking cancelled.
+    assert_eq!(pipeline.recv(), Some(None));
+    let errors: Vec<_> = pipeline.errors().collect();
+    assert!(matches!(&errors[..], [error] if matches!(error.kind, StageErrorKind::Panicked(_))));
+    for report in pipeline.join() {
+        report.result.unwrap();
+    }
+
+    let sum =
+        Pipeline::source("generate", Source::new(1..=5u32)).run(Fold::new(0, add_all_but_three));
+    assert!(matches!(sum, Err(PipelineError::Panicked { stage, .. }) if stage == "sink"));
+}
+
+#[test]
+fn cancelling_stops_every_thread() {
+    let pipeline = Pipeline::source("generate", Source::new(0..))
+        .fan_out("square", FanOut::new().workers(3), |num: u64| num * num)
+        .stage(StageConfig::new("merge").capacity(1), |num: u64| num)
+        .spawn()
+        .unwrap();
+    assert!(pipeline.recv().is_some());
+    assert!(!pipeline.running().is_empty());
+    pipeline.cancel();
+    let reports = pipeline.join();
+    // generate, the dispatcher, three workers and merge.
+    assert_eq!(reports.len(), 6);
+    assert!(reports.iter().all(|report| report.result.is_ok()));
+}
+
+/// The name of the worker thread running the stage, along with what it got.
+fn worker_name<T>(num: T) -> (String, T) {
+    let name = thread::current().name().unwrap_or_default().to_owned();
+    (name, num)
+}
+
+#[test]
+fn fan_out_replaces_its_last_worker_when_cut_off() {
+    let (gate, wait) = channel::<()>();
+    let wait = Arc::new(Mutex::new(wait));
+    let pipeline = Pipeline::source("generate", Source::new(0..6u64))
+        .fan_out(
+            StageConfig::new("square")
+                .capacity(4)
+                .backpressure(Backpressure::Disconnect),
+            FanOut::new().workers(1),
+            move |num: u64| {
+                // The first worker holds on to its first item until the gate is dropped.
+                if num == 0 {
+                    let _ = wait.lock().unwrap().recv();
+                }
+                worker_name(num)
+            },
+        )
+        .spawn()
+        .unwrap();
+    // Whatever comes after the link into the first worker filled up goes to a new one.
+    let first = pipeline.recv();
+    assert!(
+        matches!(&first, Some((worker, 4 | 5)) if worker == "square-1"),
+        "{:?}",
+        first
+    );
+    let errors: Vec<_> = pipeline.errors().collect();
+    assert!(matches!(
+        &errors[..],
+        [error] if error.stage == "square-dispatch" && matches!(error.kind, StageErrorKind::Disconnected)
+    ));
+    drop(gate);
+    for report in pipeline.join() {
+        report.result.unwrap();
+    }
+}
+
+#[test]
+fn workers_can_be_added_and_retired_while_running() {
+    let (numbers, generated) = channel();
+    let pipeline = Pipeline::source("generate", Source::from_fn(move || generated.recv().ok()))
+        .fan_out("square", FanOut::new().workers(2), |num: u64| {
+            // Slow enough for items to pile up in the workers' links.
+            thread::sleep(Duration::from_millis(1));
+            worker_name(num)
+        })
+        .spawn()
+        .unwrap();
+    let pool = pipeline.pool("square").expect("square is a fan-out stage");
+    assert_eq!(pool.len(), 2);
+    let mut outputs = Vec::new();
+    let send = |range: Range<u64>, outputs: &mut Vec<(String, u64)>| {
+        for num in range.clone() {
+            numbers.send(num).unwrap();
+        }
+        outputs.extend(range.map(|_| pipeline.recv().unwrap()));
+    };
+
+    pool.add_worker().unwrap();
+    assert_eq!(pool.len(), 3);
+    send(0..30, &mut outputs);
+    // Round robin gives the new worker its share.
+    assert!(outputs.iter().any(|(worker, _)| worker == "square-2"));
+
+    // Items already given to the workers being retired still come out.
+    for num in 30..60 {
+        numbers.send(num).unwrap();
+    }
+    pool.resize(1).unwrap();
+    assert_eq!(pool.len(), 1);
+    assert!(!pool.retire_worker(), "the last worker is never retired");
+    outputs.extend((30..60).map(|_| pipeline.recv().unwrap()));
+    let started = Instant::now();
+    while pipeline
+        .running()
+        .iter()
+        .any(|stage| stage == "square-1" || stage == "square-2")
+    {
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "retired workers never exit"
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+    let last = outputs.len();
+    send(60..70, &mut outputs);
+    assert!(outputs[last..]
+        .iter()
+        .all(|(worker, _)| worker == "square-0"));
+
+    drop(numbers);
+    assert_eq!(pipeline.recv(), None);
+    let mut nums: Vec<_> = outputs.into_iter().map(|(_, num)| num).collect();
+    nums.sort_unstable();
+    assert_eq!(nums, (0..70).collect::<Vec<_>>());
+    for report in pipeline.join() {
+        report.result.unwrap();
+    }
+}
+
+#[test]
+#[should_panic(expected = "a link holds at least one item")]
+fn links_hold_at_least_one_item() {
+    let _ = StageConfig::new("square").capacity(0);
+}
@@ -0,0 +1,105 @@
+use std::sync::mpsc::channel;
+
+use rconcurrency_stuff::sink::Collect;
+use rconcurrency_stuff::{Pipeline, Router, Rules, Source, StageConfig, StageErrorKind, Topology};
+
+/// A node telling which branch the items came through.
+fn tag(branch: &'static str) -> impl FnOnce(Pipeline<u32>) -> Pipeline<(&'static str, u32)> + Send {
+    move |input| input.stage(branch, move |num: u32| (branch, num))
+}
+
+#[test]
+fn items_go_to_the_branch_their_rules_pick() {
+    let router = Router::new(
+        Rules::new()
+            .when("small", |num: &u32| *num < 4)
+            .by_key(|num| num % 3, [(0, "fizz"), (1, "buzz")]),
+    );
+    let mut topology = Topology::new();
+    let numbers = topology.source("generate", Source::new(0..12u32));
+    let routes = topology.router("route", router);
+    let small = topology.node("small", tag("small"));
+    let fizz = topology.node("fizz", tag("fizz"));
+    let buzz = topology.node("buzz", tag("buzz"));
+    let other = topology.node("other", tag("other"));
+    topology.connect(numbers, routes.input());
+    topology.connect(routes.branch("small"), small.input());
+    topology.connect(routes.branch("fizz"), fizz.input());
+    topology.connect(routes.branch("buzz"), buzz.input());
+    topology.connect(routes.unmatched(), other.input());
+    let output = topology.node("output", |input| input.stage("output", |routed| routed));
+    for branch in [small, fizz, buzz, other] {
+        topology.connect(branch.output(), output.input());
+    }
+    let mut routed = topology.run(output.output(), Collect::new()).unwrap();
+    routed.sort_by_key(|&(_, num)| num);
+    let expected = [
+        ("small", 0),
+        ("small", 1),
+        ("small", 2),
+        ("small", 3),
+        ("buzz", 4),
+        ("other", 5),
+        ("fizz", 6),
+        ("buzz", 7),
+        ("other", 8),
+        ("fizz", 9),
+        ("buzz", 10),
+        ("other", 11),
+    ];
+    assert_eq!(routed, expected);
+}
+
+#[test]
+fn unmatched_items_go_to_dead_letters() {
+    let (dead_letters, unrouted) = channel();
+    let router = Router::new(Rules::new().when("even", |num: &u32| num % 2 == 0));
+    let mut topology = Topology::new();
+    let numbers = topology.source("generate", Source::new(0..6u32));
+    let routes = topology.router(StageConfig::new("route").dead_letters(dead_letters), router);
+    let even = topology.node("even", |input| input.stage("even", |num: u32| num));
+    topology.connect(numbers, routes.input());
+    topology.connect(routes.branch("even"), even.input());
+    assert_eq!(
+        topology.run(even.output(), Collect::new()).unwrap(),
+        [0, 2, 4]
+    );
+    let unrouted: Vec<_> = unrouted.try_iter().collect();
+    assert_eq!(unrouted.len(), 3);
+    assert!(unrouted
+        .iter()
+        .all(|error| error.stage == "route" && matches!(error.kind, StageErrorKind::Unrouted)));
+}
+
+#[test]
+fn rules_can_be_replaced_while_running() {
+    let (numbers_tx, numbers_rx) = channel();
+    let router = Router::new(
+        Rules::new()
+            .when("even", |num: &u32| num % 2 == 0)
+            .when("odd", |_| true),
+    );
+    let mut topology = Topology::new();
+    let numbers = topology.source("generate", Source::from_fn(move || numbers_rx.recv().ok()));
+    let routes = topology.router("route", router.clone());
+    let even = topology.node("even", tag("even"));
+    let odd = topology.node("odd", tag("odd"));
+    topology.connect(numbers, routes.input());
+    topology.connect(routes.branch("even"), even.input());
+    topology.connect(routes.branch("odd"), odd.input());
+    let output = topology.node("output", |input| input.stage("output", |routed| routed));
+    topology.connect(even.output(), output.input());
+    topology.connect(odd.output(), output.input());
+    let pipeline = topology.spawn(output.output()).unwrap();
+
+    numbers_tx.send(2).unwrap();
+    assert_eq!(pipeline.recv(), Some(("even", 2)));
+    router.replace(Rules::new().when("odd", |_| true));
+    numbers_tx.send(4).unwrap();
+    assert_eq!(pipeline.recv(), Some(("odd", 4)));
+    drop(numbers_tx);
+    assert_eq!(pipeline.recv(), None);
+    for report in pipeline.join() {
+        report.result.unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+use std::io::{self, Cursor};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rconcurrency_stuff::sink::Collect;
+use rconcurrency_stuff::{Pipeline, Source, StageConfig, StageErrorKind};
+
+/// Counts the items pulled out of the source, which is infinite.
+fn counted(pulled: &Arc<AtomicUsize>) -> Source<usize> {
+    let pulled = pulled.clone();
+    Source::from_fn(move || Some(pulled.fetch_add(1, Ordering::SeqCst)))
+}
+
+#[test]
+fn take_stops_after_n_items() {
+    let pulled = Arc::new(AtomicUsize::new(0));
+    // The generator holds on to `alive` until the source drops it.
+    let (alive, dropped) = channel::<()>();
+    let counter = pulled.clone();
+    let source = Source::from_fn(move || {
+        let _alive = &alive;
+        Some(counter.fetch_add(1, Ordering::SeqCst))
+    });
+    let pipeline = Pipeline::source("generate", source.take(3))
+        .spawn()
+        .unwrap();
+    let items: Vec<_> = pipeline.iter().collect();
+    assert_eq!(items, [0, 1, 2]);
+    for report in pipeline.join() {
+        report.result.unwrap();
+    }
+    // Nothing was pulled past the last item, and the source let go of the generator.
+    assert_eq!(pulled.load(Ordering::SeqCst), 3);
+    assert_eq!(dropped.try_recv(), Err(TryRecvError::Disconnected));
+}
+
+#[test]
+fn stop_when_leaves_out_the_boundary_item() {
+    let pulled = Arc::new(AtomicUsize::new(0));
+    let items = Pipeline::source("generate", counted(&pulled).stop_when(|&num| num == 3))
+        .run(Collect::new())
+        .unwrap();
+    assert_eq!(items, [0, 1, 2]);
+    // The boundary item was pulled to be checked, but nothing after it.
+    assert_eq!(pulled.load(Ordering::SeqCst), 4);
+
+    // The first item may be the boundary already.
+    let items = Pipeline::source("generate", Source::new(0..10).stop_when(|_| true))
+        .run(Collect::new())
+        .unwrap();
+    assert!(items.is_empty());
+}
+
+#[test]
+fn lines_emit_read_errors_too() {
+    // The second line is not valid UTF-8.
+    let reader = Cursor::new(b"one\n\xff\nthree".to_vec());
+    let (dead_letters, rejected) = channel();
+    let lines = Pipeline::source("read", Source::lines(reader))
+        .try_stage(
+            StageConfig::new("check").dead_letters(dead_letters),
+            |line: io::Result<String>| line,
+        )
+        .run(Collect::new())
+        .unwrap();
+    assert_eq!(lines, ["one", "three"]);
+    let errors: Vec<_> = rejected.try_iter().collect();
+    assert!(matches!(
+        &errors[..],
+        [error] if matches!(&error.kind, StageErrorKind::Rejected(message) if message.contains("UTF-8"))
+    ));
+}
+
+#[test]
+fn rate_limited_sources_space_their_items_out() {
+    let started = Instant::now();
+    let items = Pipeline::source("generate", Source::new(0..5).rate_limit(50))
+        .run(Collect::new())
+        .unwrap();
+    assert_eq!(items, [0, 1, 2, 3, 4]);
+    // Four gaps of 20ms between five items, with some leeway the other way.
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(80), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+}
@@ -0,0 +1,226 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rconcurrency_stuff::error::TopologyError;
+use rconcurrency_stuff::sink::{Collect, ForEach};
+use rconcurrency_stuff::{
+    Backpressure, PipelineError, Source, StageConfig, StageErrorKind, Topology,
+};
+
+fn topology_error(result: Result<impl Sized, PipelineError>) -> TopologyError {
+    match result {
+        Err(PipelineError::Topology(err)) => err,
+        Err(err) => panic!("unexpected error: {err}"),
+        Ok(_) => panic!("the topology should be invalid"),
+    }
+}
+
+#[test]
+fn broadcast_branches_zip_back_together() {
+    let mut topology = Topology::new();
+    let numbers = topology.source("generate", Source::new(1..=5u64));
+    let square = topology.node("square", |input| {
+        input.stage("square", |num: u64| num * num)
+    });
+    let double = topology.node("double", |input| input.stage("double", |num: u64| num * 2));
+    let zip = topology.zip("zip");
+    topology.broadcast(numbers);
+    topology.connect(numbers, square.input());
+    topology.connect(numbers, double.input());
+    topology.connect(square.output(), zip.left());
+    topology.connect(double.output(), zip.right());
+    let pipeline = topology.spawn(zip.output()).unwrap();
+    let pairs: Vec<_> = pipeline.iter().collect();
+    assert_eq!(pairs, [(1, 2), (4, 4), (9, 6), (16, 8), (25, 10)]);
+    for report in pipeline.join() {
+        report.result.unwrap();
+    }
+}
+
+/// A subscriber that takes the first item in then waits until `gate` is dropped,
+/// telling `seen` about every item it gets.
+fn slow_subscriber(gate: Receiver<()>, seen: Sender<u32>) -> ForEach<impl FnMut(u32) + Send> {
+    ForEach::new(move |num| {
+        seen.send(num).unwrap();
+        let _ = gate.recv();
+    })
+}
+
+#[test]
+fn slow_subscribers_are_cut_off() {
+    let (gate, wait) = channel();
+    let (seen_tx, seen) = channel();
+    let mut topology = Topology::new();
+    let numbers = topology.source("generate", Source::new(0..100u32));
+    let fast = topology.node("fast", |input| input.sink("collect", Collect::new()));
+    let slow = topology.sink(
+        StageConfig::new("slow")
+            .capacity(1)
+            .backpressure(Backpressure::Disconnect),
+        slow_subscriber(wait, seen_tx),
+    );
+    topology.broadcast(numbers);
+    topology.connect(numbers, fast.input());
+    topology.connect(numbers, slow);
+    let pipeline = topology.spawn(fast.output()).unwrap();
+    assert_eq!(pipeline.recv(), Some((0..100).collect()));
+    drop(gate);
+    let errors: Vec<_> = pipeline.errors().collect();
+    assert!(matches!(
+        &errors[..],
+        [error] if error.stage == "generate-split" && matches!(error.kind, StageErrorKind::Disconnected)
+    ));
+    for report in pipeline.join() {
+        report.result.unwrap();
+    }
+    // What was already in its buffer when it was cut off still got through.
+    let seen: Vec<_> = seen.try_iter().collect();
+    assert!(seen == [0] || seen == [0, 1], "{:?}", seen);
+}
+
+#[test]
+fn slow_subscribers_miss_items() {
+    let (gate, wait) = channel();
+    let (seen_tx, seen) = channel();
+    let mut topology = Topology::new();
+    let numbers = topology.source("generate", Source::new(0..100u32));
+    let fast = topology.node("fast", |input| input.sink("collect", Collect::new()));
+    let slow = topology.sink(
+        StageConfig::new("slow")
+            .capacity(1)
+            .backpressure(Backpressure::DropNewest),
+        slow_subscriber(wait, seen_tx),
+    );
+    topology.broadcast(numbers);
+    topology.connect(numbers, fast.input());
+    topology.connect(numbers, slow);
+    let pipeline = topology.spawn(fast.output()).unwrap();
+    assert_eq!(pipeline.recv(), Some((0..100).collect()));
+    let links = pipeline.links();
+    let slow: Vec<_> = links.iter().filter(|link| link.name == "slow").collect();
+    assert!(matches!(&slow[..], [link] if link.capacity == Some(1)));
+    drop(gate);
+    for report in pipeline.join() {
+        report.result.unwrap();
+    }
+    let seen: Vec<_> = seen.try_iter().collect();
+    assert!(seen.len() < 100 && seen.is_sorted(), "{:?}", seen);
+}
+
+#[test]
+fn nodes_get_the_link_they_are_set_up_with() {
+    let (gate, wait) = channel::<()>();
+    let mut topology = Topology::new();
+    let numbers = topology.source("generate", Source::new(0..50u32));
+    let slow = topology.node(
+        StageConfig::new("slow")
+            .capacity(1)
+            .backpressure(Backpressure::Disconnect),
+        |input| {
+            input.stage("slow", move |num: u32| {
+                let _ = wait.recv();
+                num
+            })
+        },
+    );
+    topology.connect(numbers, slow.input());
+    let pipeline = topology.spawn(slow.output()).unwrap();
+    // The slow stage holds on to its first item until the gate is dropped,
+    // so the link into it fills up and it is cut off.
+    let started = Instant::now();
+    while !pipeline
+        .errors()
+        .any(|error| matches!(error.kind, StageErrorKind::Disconnected))
+    {
+        assert!(started.elapsed() < Duration::from_secs(5), "never cut off");
+        thread::sleep(Duration::from_millis(1));
+    }
+    drop(gate);
+    let seen: Vec<_> = pipeline.iter().collect();
+    assert!(seen == [0] || seen == [0, 1], "{:?}", seen);
+    for report in pipeline.join() {
+        report.result.unwrap();
+    }
+}
+
+#[test]
+fn filters_route_items_to_branches_merged_back() {
+    let mut topology = Topology::new();
+    let numbers = topology.source("generate", Source::new(0..100u64));
+    let even = topology.node("even", |input| input.stage("halve", |num: u64| num / 2));
+    let odd = topology.node("odd", |input| {
+        input.stage("negate", |num: u64| -(num as i64))
+    });
+    let positive = topology.node("positive", |input| {
+        input.stage("widen", |num: u64| num as i64)
+    });
+    let collect = topology.node("collect", |input| input.sink("collect", Collect::new()));
+    topology.connect_if(numbers, even.input(), |num| num % 2 == 0);
+    topology.connect(numbers, odd.input());
+    topology.connect(even.output(), positive.input());
+    topology.connect(positive.output(), collect.input());
+    topology.connect(odd.output(), collect.input());
+    let mut results = topology
+        .spawn(collect.output())
+        .unwrap()
+        .recv()
+        .expect("the sink emits what it collected");
+    results.sort();
+    let mut expected: Vec<i64> = (0..100)
+        .map(|num| if num % 2 == 0 { num / 2 } else { -num })
+        .collect();
+    expected.sort();
+    assert_eq!(results, expected);
+}
+
+#[test]
+fn cycles_are_rejected() {
+    let mut topology = Topology::new();
+    let numbers = topology.source("generate", Source::new(0..10u32));
+    let first = topology.node("first", |input| input.stage("first", |num: u32| num));
+    let second = topology.node("second", |input| input.stage("second", |num: u32| num));
+    topology.connect(numbers, first.input());
+    topology.connect(first.output(), second.input());
+    topology.connect_if(second.output(), first.input(), |num| *num > 0);
+    let err = topology_error(topology.spawn(second.output()));
+    assert!(
+        matches!(&err, TopologyError::Cycle(cycle) if cycle == &["first", "second", "first"]),
+        "{err}"
+    );
+}
+
+#[test]
+fn mistakes_are_caught_before_spawning() {
+    let mut topology = Topology::new();
+    let numbers = topology.source("generate", Source::new(0..10u32));
+    let square = topology.node("square", |input| {
+        input.stage("square", |num: u32| num * num)
+    });
+    topology.connect(numbers, square.input());
+    let zip = topology.zip::<u32, u32>("zip");
+    topology.connect(square.output(), zip.left());
+    let err = topology_error(topology.spawn(zip.output()));
+    assert!(matches!(&err, TopologyError::UnconnectedInput(port) if port == "zip.right"));
+
+    let mut topology = Topology::new();
+    let numbers = topology.source("generate", Source::new(0..10u32));
+    let all = topology.node("all", |input| input.stage("all", |num: u32| num));
+    let some = topology.node("some", |input| input.stage("some", |num: u32| num));
+    topology.connect(numbers, all.input());
+    topology.connect_if(numbers, some.input(), |num| num % 2 == 0);
+    topology.connect(some.output(), all.input());
+    let err = topology_error(topology.spawn(all.output()));
+    assert!(
+        matches!(&err, TopologyError::Unreachable { from, to } if from == "generate" && to == "some")
+    );
+
+    let mut topology = Topology::new();
+    let numbers = topology.source("generate", Source::new(0..10u32));
+    let square = topology.node("generate", |input| {
+        input.stage("square", |num: u32| num * num)
+    });
+    topology.connect(numbers, square.input());
+    let err = topology_error(topology.spawn(square.output()));
+    assert!(matches!(&err, TopologyError::DuplicateNode(name) if name == "generate"));
+}
@@ -0,0 +1,129 @@
+use std::sync::mpsc::{channel, sync_channel, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use rconcurrency_stuff::log::ItemId;
+use rconcurrency_stuff::watchdog::{Activity, Stuck};
+use rconcurrency_stuff::{Pipeline, Source, StageConfig, Watchdog};
+
+#[test]
+fn shutdown_timeout_waits_for_stages_that_stop() {
+    let pipeline = Pipeline::source("generate", Source::new(0..))
+        .stage("square", |num: u64| num * num)
+        .spawn()
+        .unwrap();
+    assert!(pipeline.recv().is_some());
+    let reports = pipeline.shutdown_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(reports.len(), 2);
+    assert!(reports.iter().all(|report| report.result.is_ok()));
+}
+
+#[test]
+fn status_shows_what_stages_are_blocked_on() {
+    let pipeline = Pipeline::source("generate", Source::new(0..))
+        .stage(StageConfig::new("square").capacity(1), |num: u64| num * num)
+        .spawn()
+        .unwrap();
+    // Nobody reads the output, so everything upstream ends up waiting for room.
+    thread::sleep(Duration::from_millis(50));
+    let status = pipeline.status();
+    assert_eq!(
+        status[1].activity,
+        Activity::Sending {
+            link: "square".to_owned()
+        }
+    );
+    pipeline.shutdown();
+}
+
+#[test]
+fn stages_that_never_exit_are_reported() {
+    // Never sent to, and kept alive until the end of the test.
+    let (_never, wait) = sync_channel::<()>(0);
+    let pipeline = Pipeline::source("generate", Source::new(1..))
+        .stage("stuck", move |num: u64| {
+            if num == 3 {
+                let _ = wait.recv();
+            }
+            num
+        })
+        .spawn()
+        .unwrap();
+    assert_eq!(pipeline.recv(), Some(1));
+    assert_eq!(pipeline.recv(), Some(2));
+
+    let stuck = pipeline
+        .shutdown_timeout(Duration::from_millis(50))
+        .unwrap_err();
+    assert_eq!(stuck.stages.len(), 1);
+    assert_eq!(stuck.stages[0].stage, "stuck");
+    assert_eq!(stuck.stages[0].activity, Activity::Running);
+    assert_eq!(stuck.stages[0].item, Some(ItemId(2)));
+    assert!(stuck.to_string().contains("stuck: running for"));
+}
+
+#[test]
+fn the_watchdog_reports_stages_that_never_exit() {
+    let (_never, wait) = sync_channel::<()>(0);
+    let (started_tx, started) = channel();
+    let (reported, stuck) = channel();
+    let pipeline = Pipeline::source("generate", Source::new(1..))
+        .stage("stuck", move |num: u64| {
+            let _ = started_tx.send(());
+            let _ = wait.recv();
+            num
+        })
+        .watchdog(
+            Watchdog::new(Duration::from_millis(50)).on_stuck(move |stuck| {
+                let _ = reported.send(stuck.clone());
+            }),
+        )
+        .spawn()
+        .unwrap();
+    started.recv().unwrap();
+    pipeline.cancel();
+    let stuck = stuck.recv_timeout(Duration::from_secs(5)).unwrap();
+    let stages: Vec<_> = stuck.stages.iter().map(|status| &status.stage).collect();
+    assert_eq!(stages, ["stuck"]);
+}
+
+#[test]
+fn the_watchdog_is_stopped_along_with_the_pipeline() {
+    let (reported, stuck) = channel::<Stuck>();
+    let pipeline = Pipeline::source("generate", Source::new(0..))
+        .stage("square", |num: u64| num * num)
+        .watchdog(
+            Watchdog::new(Duration::from_secs(5)).on_stuck(move |stuck| {
+                let _ = reported.send(stuck.clone());
+            }),
+        )
+        .spawn()
+        .unwrap();
+    assert!(pipeline.recv().is_some());
+    pipeline.shutdown();
+    // `on_stuck` went away with the watchdog's thread, without being called.
+    assert_eq!(stuck.try_recv().unwrap_err(), TryRecvError::Disconnected);
+
+    // Stuck stages are reported by `shutdown_timeout`, and not by the watchdog later on.
+    let (_never, wait) = sync_channel::<()>(0);
+    let (started_tx, started) = channel();
+    let (reported, stuck) = channel::<Stuck>();
+    let pipeline = Pipeline::source("generate", Source::new(0..))
+        .stage("stuck", move |num: u64| {
+            let _ = started_tx.send(());
+            let _ = wait.recv();
+            num
+        })
+        .watchdog(
+            Watchdog::new(Duration::from_millis(200)).on_stuck(move |stuck| {
+                let _ = reported.send(stuck.clone());
+            }),
+        )
+        .spawn()
+        .unwrap();
+    started.recv().unwrap();
+    assert!(pipeline
+        .shutdown_timeout(Duration::from_millis(50))
+        .is_err());
+    assert_eq!(stuck.try_recv().unwrap_err(), TryRecvError::Disconnected);
+}
@@ -0,0 +1,180 @@
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rconcurrency_stuff::error::StageErrorKind;
+use rconcurrency_stuff::sink::Collect;
+use rconcurrency_stuff::window::{CheckedSum, Count, Max, Min, Sum};
+use rconcurrency_stuff::{Pipeline, Source, StageConfig, Window, Windowed};
+
+/// A reading taken at some second. Readings compare by value first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Reading {
+    value: u64,
+    at: u64,
+}
+
+fn readings(readings: &[(u64, u64)]) -> Source<Reading> {
+    let readings: Vec<_> = readings
+        .iter()
+        .map(|&(at, value)| Reading { at, value })
+        .collect();
+    Source::new(readings)
+}
+
+fn at(reading: &Reading) -> Duration {
+    Duration::from_secs(reading.at)
+}
+
+/// Windows, with their bounds in seconds.
+fn secs<A>(windows: Vec<Windowed<A>>) -> Vec<(u64, u64, A)> {
+    windows
+        .into_iter()
+        .map(|window| (window.start.as_secs(), window.end.as_secs(), window.value))
+        .collect()
+}
+
+#[test]
+fn tumbling_windows_by_event_time() {
+    let windows = Pipeline::source(
+        "generate",
+        readings(&[(0, 1), (3, 2), (5, 3), (9, 4), (12, 5)]),
+    )
+    .window(
+        "window",
+        Window::tumbling(Duration::from_secs(5)).event_time(at),
+        (Count, (Min, Max)),
+    )
+    .run(Collect::new())
+    .unwrap();
+    let windows: Vec<_> = secs(windows)
+        .into_iter()
+        .map(|(start, end, (count, (min, max)))| (start, end, count, min.value, max.value))
+        .collect();
+    assert_eq!(
+        windows,
+        [(0, 5, 2, 1, 2), (5, 10, 2, 3, 4), (10, 15, 1, 5, 5)]
+    );
+}
+
+#[test]
+fn checked_sums_report_windows_that_overflow() {
+    // The last digit is the second the number came at.
+    let windows = Pipeline::source("generate", Source::new([100u8, 120, 201, 101, 42]))
+        .window(
+            "window",
+            Window::tumbling(Duration::from_secs(1))
+                .event_time(|num: &u8| Duration::from_secs(u64::from(num % 10))),
+            CheckedSum,
+        )
+        .run(Collect::new())
+        .unwrap();
+    let windows: Vec<_> = secs(windows)
+        .into_iter()
+        .map(|(start, _, sum)| (start, sum.map_err(|error| error.to_string())))
+        .collect();
+    assert_eq!(
+        windows,
+        [
+            (0, Ok(220)),
+            (1, Err("the sum of 2 items does not fit in a u8".to_owned())),
+            (2, Ok(42)),
+        ]
+    );
+}
+
+#[test]
+fn sliding_windows_overlap() {
+    let windows = Pipeline::source("generate", readings(&[(1, 1), (3, 1), (5, 1)]))
+        .window(
+            "window",
+            Window::sliding(Duration::from_secs(4), Duration::from_secs(2)).event_time(at),
+            Count,
+        )
+        .run(Collect::new())
+        .unwrap();
+    assert_eq!(secs(windows), [(0, 4, 2), (2, 6, 2), (4, 8, 1)]);
+}
+
+#[test]
+fn sessions_grow_until_a_gap() {
+    let windows = Pipeline::source(
+        "generate",
+        readings(&[(0, 1), (2, 1), (10, 1), (4, 1), (11, 1), (30, 1)]),
+    )
+    .window(
+        "window",
+        Window::session(Duration::from_secs(3))
+            .event_time(at)
+            .allowed_lateness(Duration::from_secs(10)),
+        Count,
+    )
+    .run(Collect::new())
+    .unwrap();
+    assert_eq!(secs(windows), [(0, 7, 3), (10, 14, 2), (30, 33, 1)]);
+}
+
+#[test]
+fn late_items_go_to_dead_letters() {
+    let (dead_letters, late) = channel();
+    let windows = Pipeline::source(
+        "generate",
+        readings(&[(0, 1), (6, 2), (4, 3), (1, 4), (7, 5)]),
+    )
+    .window(
+        StageConfig::new("window").dead_letters(dead_letters),
+        Window::tumbling(Duration::from_secs(5))
+            .event_time(at)
+            .allowed_lateness(Duration::from_secs(2)),
+        Count,
+    )
+    .run(Collect::new())
+    .unwrap();
+    // The watermark is at 4s once the item at 6s is in: the item at 4s is just in time.
+    // Then the item at 7s takes it to 5s, after the one at 1s already came in.
+    assert_eq!(secs(windows), [(0, 5, 3), (5, 10, 2)]);
+    assert_eq!(late.try_iter().count(), 0);
+
+    let (dead_letters, late) = channel();
+    let windows = Pipeline::source("generate", readings(&[(0, 1), (8, 2), (4, 3)]))
+        .window(
+            StageConfig::new("window").dead_letters(dead_letters),
+            Window::tumbling(Duration::from_secs(5))
+                .event_time(at)
+                .allowed_lateness(Duration::from_secs(2)),
+            Count,
+        )
+        .run(Collect::new())
+        .unwrap();
+    assert_eq!(secs(windows), [(0, 5, 1), (5, 10, 1)]);
+    let late: Vec<_> = late.try_iter().collect();
+    assert_eq!(late.len(), 1);
+    assert!(matches!(late[0].kind, StageErrorKind::Late(by) if by == Duration::from_secs(2)));
+}
+
+#[test]
+fn wall_clock_windows_close_as_time_goes_by() {
+    let started = Instant::now();
+    let mut items = 1..=3u64;
+    let source = Source::from_fn(move || {
+        let item = items.next();
+        if item.is_none() {
+            thread::sleep(Duration::from_millis(500));
+        }
+        item
+    });
+    let pipeline = Pipeline::source("generate", source)
+        .window("window", Window::tumbling(Duration::from_millis(50)), Sum)
+        .spawn()
+        .unwrap();
+    // The items may straddle two windows, which close by themselves
+    // without waiting for more items or for the source to run out.
+    let mut sum = 0;
+    while sum < 6 {
+        let window = pipeline.recv().unwrap();
+        assert_eq!(window.end - window.start, Duration::from_millis(50));
+        sum += window.value;
+    }
+    assert!(started.elapsed() < Duration::from_millis(400));
+    pipeline.shutdown();
+}